@@ -1,12 +1,9 @@
 _tabry_executable=${_tabry_executable:-$(cd -- "$( dirname -- "${BASH_SOURCE[0]}" )"/.. &> /dev/null && pwd)/target/debug/tabry}
 
 _tabry_complete_all_UNIQ_FN_ID() {
-  if [[ -z "$TABRY_IMPORT_PATH" ]]; then
-    if [[ -n "$_tabry_imports_path" ]]; then
-      export TABRY_IMPORT_PATH="$_tabry_imports_path"
-    else
-      TABRY_IMPORT_PATH=~/.local/share/tabry
-    fi
+  # tabry also looks in project .tabry dirs, $XDG_DATA_HOME/tabry and $XDG_DATA_DIRS/*/tabry
+  if [[ -z "$TABRY_IMPORT_PATH" && -n "$_tabry_imports_path" ]]; then
+    export TABRY_IMPORT_PATH="$_tabry_imports_path"
   fi
 
   [[ -x "$_tabry_executable" ]] || { echo "tabry_bash.sh: error: can't find tabry executable at $_tabry_executable -- if you are using the script from source rather than using via 'tabry bash', perhaps you need to run 'cargo build'?"; return 1; }
@@ -27,7 +24,7 @@ _tabry_completions_UNIQ_FN_ID() {
 }
 
 _tabry_set_compreply_from_lines_UNIQ_FN_ID() {
-  # Feed in lines from a variable, quoting each line.
+  # Feed in lines from a variable (already escaped by tabry complete --escape), skipping empty ones.
   # Using readarray is much faster than using += many times to build the array.
   local lines="$1"
   local saveifs="$IFS"
@@ -36,13 +33,52 @@ _tabry_set_compreply_from_lines_UNIQ_FN_ID() {
     IFS=$'\n'
     while IFS= read -r line; do
       if [[ -n "$line" ]]; then
-        printf '%q\n' "$line"
+        printf '%s\n' "$line"
       fi
     done <<< "$lines"
     IFS="$saveifs"
   )
 }
 
+# Complete using another command's own completion, for "opts delegate". The argument is the
+# tab-separated command line to complete, as computed by tabry (the delegate command plus any
+# args being forwarded); its last word is the one being completed.
+_tabry_delegate_UNIQ_FN_ID() {
+  local IFS=$' \t\n'
+  local -a delegate_words
+  # terminate every field so a trailing empty word (nothing typed yet) is kept
+  readarray -t -d $'\t' delegate_words < <(printf '%s\t' "$1")
+  local delegate_cmd="${delegate_words[0]}"
+  local n_words="${#delegate_words[@]}"
+
+  if [[ $n_words -le 1 ]]; then
+    # completing the command name itself
+    COMPREPLY+=($(compgen -c -- "$delegate_cmd"))
+    return
+  fi
+
+  local complete_fn=$(complete -p "$delegate_cmd" 2>/dev/null | sed -n 's/.*-F \([^ ]*\) .*/\1/p')
+  if [[ -z "$complete_fn" ]] && declare -F _completion_loader >/dev/null; then
+    _completion_loader "$delegate_cmd"
+    complete_fn=$(complete -p "$delegate_cmd" 2>/dev/null | sed -n 's/.*-F \([^ ]*\) .*/\1/p')
+  fi
+  if [[ -z "$complete_fn" ]]; then
+    echo "Error: Could not find completion function for $delegate_cmd" >&2
+    return 1
+  fi
+
+  # The completion variables are dynamically scoped, so making them local here means the
+  # delegate's completion function sees the delegated command line, and ours are restored after.
+  local -a COMP_WORDS=("${delegate_words[@]}")
+  local COMP_CWORD=$((n_words - 1))
+  local COMP_LINE="${delegate_words[*]}"
+  local COMP_POINT="${#COMP_LINE}"
+  local -a tabry_reply=("${COMPREPLY[@]}")
+  COMPREPLY=()
+  "$complete_fn" "$delegate_cmd" "${COMP_WORDS[COMP_CWORD]}" "${COMP_WORDS[COMP_CWORD-1]}"
+  COMPREPLY=("${tabry_reply[@]}" "${COMPREPLY[@]}")
+}
+
 # This is unchanged from ruby tabry, except to remove the second arg
 _tabry_completions_internal_UNIQ_FN_ID()
 {
@@ -52,8 +88,25 @@ _tabry_completions_internal_UNIQ_FN_ID()
   local saveifs="$IFS"
   IFS=$'\n'
 
-  [[ -n "$TABRY_DEBUG" ]] && printf "%q %q %q %q\n" "$tabry_bash_executable" complete "$COMP_LINE" "$COMP_POINT"
-  local result=$("$tabry_bash_executable" complete "$COMP_LINE" "$COMP_POINT")
+  local result
+  local tabry_words_mode
+  # COMP_TYPE tells tabry whether bash is listing options (with descriptions) or inserting one
+  local comp_type_args=()
+  [[ -n "$COMP_TYPE" ]] && comp_type_args=(--comp-type "$COMP_TYPE")
+  if declare -F _get_comp_words_by_ref >/dev/null; then
+    # With bash-completion, use the words as bash split them, but rejoined where they were split
+    # on : and = (COMP_WORDBREAKS), so e.g. "host:path" is one word
+    tabry_words_mode=1
+    local cur words cword
+    _get_comp_words_by_ref -n =: -c cur -w words -i cword
+    [[ -n "$TABRY_DEBUG" ]] && printf "%q " "$tabry_bash_executable" complete-words --escape "${comp_type_args[@]}" --quoted --cword "$cword" --cur "$cur" -- "${words[@]}" && echo
+    result=$(printf '%s\0' "${words[@]}" | "$tabry_bash_executable" complete-words --escape "${comp_type_args[@]}" --quoted --stdin --cword "$cword" --cur "$cur")
+  else
+    [[ -n "$TABRY_DEBUG" ]] && printf "%q " "$tabry_bash_executable" complete --escape "${comp_type_args[@]}" "$COMP_LINE" "$COMP_POINT" && echo
+    result=$("$tabry_bash_executable" complete --escape "${comp_type_args[@]}" "$COMP_LINE" "$COMP_POINT")
+  fi
+  # tabry already gives options in the order they should be shown (needs bash 4.4+)
+  compopt -o nosort 2>/dev/null
   local specials
   local specials_line
 
@@ -76,74 +129,40 @@ _tabry_completions_internal_UNIQ_FN_ID()
           printf "%q\n" "$filename"
         done
         ))
+      elif [[ "$specials_line" == "nospace" ]]; then
+        # the options are continued (directories, --flag=), so don't add a space after them
+        compopt -o nospace
       elif [[ "$specials_line" == "dir" ]]; then
         # Directory special
         # If there are only directory results, use nospace to not add a space after it,
         # like "cd" tab completion does.
         [[ ${#COMPREPLY[@]} -eq 0 ]] && compopt -o nospace
         compopt -o plusdirs
-      elif [[ "$specials" == "description_if_optionless" ]]; then
-        # "description_if_optionless" special: Options are are meant to be description or examples
-        # and not actual options. Add an empty option so we won't tab complete.
-        compopt -o nosort
-        COMPREPLY+=('')
-      elif [[ "$specials_line" == "delegate "* ]]; then
-        local delegate_cmd="${specials_line#delegate }"
-
-        # split delegate_cmd to get the actual command (first word):
-        # this is not reliable but will work for now...
-        local delegate_cmd_arg0="${delegate_cmd%% *}"
-        local complete_fn=$(complete -p "$delegate_cmd_arg0" 2>/dev/null | sed 's/.*-F \([^ ]*\) .*/\1/')
-        if [[ -z "$complete_fn" ]]; then
-          _completion_loader "$delegate_cmd_arg0"
-        fi
-        complete_fn=$(complete -p "$delegate_cmd_arg0" 2>/dev/null | sed 's/.*-F \([^ ]*\) .*/\1/')
-        if [[ -z "$complete_fn" ]]; then
-          echo "Error: Could not find completion function for $delegate_cmd_arg0" >&2
-          return 1
-        fi
-
-        # Backup
-        local comp_cword="$COMP_CWORD"
-        local comp_point="$COMP_POINT"
-        local comp_line="$COMP_LINE"
-        local comp_words=("${COMP_WORDS[@]}")
-        local comp_key="$COMP_KEY"
-        local comp_type="$COMP_TYPE"
-        local comp_reply=("${COMPREPLY[@]}")
-
-        # get completions -> COMPREPLY
-        IFS="$saveifs"
-        COMP_LINE="$delegate_cmd"
-        COMP_POINT="${#delegate_cmd}"
-        #__compal__split_cmd_line "$COMP_LINE"
-        COMP_WORDS=("${__compal__retval[@]}" "")
-        COMP_WORDS=($delegate_cmd "")
-        COMP_CWORD=$((${#COMP_WORDS[@]} - 1))
-        COMPREPLY=()
-        "$complete_fn"
-
-        # Reset the completion variables
-        IFS=$'\n'
-        COMP_CWORD="$comp_cword"
-        COMP_POINT="$comp_point"
-        COMP_LINE="$comp_line"
-        COMP_WORDS=("${comp_words[@]}")
-        COMP_KEY="$comp_key"
-        COMP_TYPE="$comp_type"
-
-        # Concatenate the completions
-        COMPREPLY=("${comp_reply[@]}" "${COMPREPLY[@]}")
+      elif [[ "$specials_line" == "description_if_optionless"$'\t'* ]]; then
+        # "description_if_optionless" special: the arg has no options, so show what is expected
+        # instead. Fields (name, title, description) are tab-separated and may be empty.
+        local hint_rest="${specials_line#description_if_optionless$'\t'}"
+        local hint_name="${hint_rest%%$'\t'*}"
+        hint_rest="${hint_rest#*$'\t'}"
+        local hint_title="${hint_rest%%$'\t'*}"
+        local hint_desc="${hint_rest#*$'\t'}"
+        local hint="<${hint_title:-$hint_name}>${hint_desc:+ $hint_desc}"
+        # Offer it along with an empty option, so it is listed but nothing is inserted.
+        COMPREPLY+=("$hint" '')
+      elif [[ "$specials_line" == "command" ]]; then
+        # Command special: the name of a command to run (opts command)
+        COMPREPLY+=($(compgen -c -- "${COMP_WORDS[$COMP_CWORD]}"))
+      elif [[ "$specials_line" == "delegate_argv"$'\t'* ]]; then
+        _tabry_delegate_UNIQ_FN_ID "${specials_line#delegate_argv$'\t'}"
       fi
     done <<< "$specials"
   else
     _tabry_set_compreply_from_lines_UNIQ_FN_ID "$result"
-    COMPREPLY=()
-    while IFS= read -r line; do
-      COMPREPLY+=($(printf "%q" "$line"))
-    done <<< "$result"
   fi
 
+  # bash only replaces the part of the word after the last ":", so remove what comes before it
+  [[ -n "$tabry_words_mode" ]] && declare -F __ltrim_colon_completions >/dev/null && __ltrim_colon_completions "$cur"
+
   IFS="$saveifs"
   [[ -n "$TABRY_DEBUG" ]] && echo -n tabry end bash: && date +%s.%N >&2
 }
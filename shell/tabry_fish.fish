@@ -1,5 +1,3 @@
-set -x TABRY_IMPORT_PATH "$TABRY_IMPORT_PATH:$HOME/.local/share/tabry"
-
 if not set -q _tabry_executable_UNIQ_FN_ID
   set script_dir (dirname (status filename))
   set -g _tabry_executable_UNIQ_FN_ID "$script_dir/target/debug/tabry"
@@ -7,7 +5,8 @@ end
 
 function tabry_completion_init_UNIQ_FN_ID
   set cmd $argv[1]
-  complete -c "$cmd" -f -a "(__tabry_offer_completions_UNIQ_FN_ID)"
+  # -k: keep the order tabry gives the options in
+  complete -c "$cmd" -f -k -a "(__tabry_offer_completions_UNIQ_FN_ID)"
 end
 
 # Init completions for all commands in the path
@@ -31,6 +30,9 @@ function __tabry_parse_results_UNIQ_FN_ID
   set -l done_completions "false"
   set -l offer_dirs "false"
   set -l offer_files "false"
+  set -l offer_commands "false"
+  set -l hint
+  set -l delegate_words
 
   for line in $argv
     if test "x$line" = "x"
@@ -42,6 +44,19 @@ function __tabry_parse_results_UNIQ_FN_ID
       set offer_files true
     else if test "$line" = "dir"
       set offer_dirs true
+    else if test "$line" = "command"
+      set offer_commands true
+    else if string match -q -r '^description_if_optionless\t' -- $line
+      # the arg has no options: fields are name, title, description (any may be empty)
+      set -l fields (string split \t -- $line)
+      set -l label $fields[3]
+      test -n "$label"; or set label $fields[2]
+      set hint "<$label>"
+      test -n "$fields[4]"; and set hint "$hint $fields[4]"
+    else if string match -q -r '^delegate_argv\t' -- $line
+      # the command line to complete with another command's completion; the last word is the
+      # one being completed
+      set delegate_words (string split \t -- $line)[2..-1]
     end
   end
 
@@ -49,6 +64,23 @@ function __tabry_parse_results_UNIQ_FN_ID
     echo $completion
   end
 
+  # Show the hint as the description of the token already typed, so completing it is a no-op
+  if test -n "$hint" -a (count $completions) -eq 0
+    printf '%s\t%s\n' (commandline -ct) $hint
+  end
+
+  if test "$offer_commands" = "true"
+    __fish_complete_command
+  end
+
+  if test (count $delegate_words) -gt 1
+    # quote the words before the one being completed, which is passed on as typed
+    set -l delegate_line (string escape -- $delegate_words[1..-2]) $delegate_words[-1]
+    complete -C (string join ' ' -- $delegate_line)
+  else if test (count $delegate_words) -eq 1
+    complete -C $delegate_words[1]
+  end
+
   if test "$offer_dirs" = "true"
     __fish_complete_directories (__tabry_get_token_on_cursor_UNIQ_FN_ID $cmd $cursor_position)
   else if test "$offer_files" = "true"
@@ -105,7 +137,11 @@ function __tabry_offer_completions_UNIQ_FN_ID
   set cursor_position (commandline -C)
   set cmd (commandline)
 
-  set -l result ($_tabry_executable_UNIQ_FN_ID complete --include-descriptions "$cmd" "$cursor_position")
+  # the words before the cursor, as fish split them (unquoted), and the token being completed up
+  # to the cursor, as typed
+  set -l words (commandline -opc)
+  set -l cur (commandline -ct)
+  set -l result (printf '%s\0' $words | $_tabry_executable_UNIQ_FN_ID complete-words --stdin --include-descriptions --cword (count $words) --cur "$cur")
 
   # get the last item
   
@@ -1,52 +1,67 @@
-# As of Sep 2024 this is the same as the bash script
-# but with readarray replaced, plus these autoloads
+# As of Sep 2024 this was the same as the bash script but with readarray replaced. It now uses
+# zsh's completion system directly (compdef/compadd) rather than bashcompinit, so candidates can
+# be added unsorted, and specials can use zsh completion functions.
 
 autoload -U +X compinit && compinit
-autoload -U +X bashcompinit && bashcompinit
 
 _tabry_executable=${_tabry_executable:-$(cd -- "$( dirname -- "${BASH_SOURCE[0]}" )"/.. &> /dev/null && pwd)/target/debug/tabry}
 
 _tabry_complete_all_UNIQ_FN_ID() {
-  if [[ -z "$TABRY_IMPORT_PATH" ]]; then
-    if [[ -n "$_tabry_imports_path" ]]; then
-      export TABRY_IMPORT_PATH="$_tabry_imports_path"
-    else
-      TABRY_IMPORT_PATH=~/.local/share/tabry
-    fi
+  # tabry also looks in project .tabry dirs, $XDG_DATA_HOME/tabry and $XDG_DATA_DIRS/*/tabry
+  if [[ -z "$TABRY_IMPORT_PATH" && -n "$_tabry_imports_path" ]]; then
+    export TABRY_IMPORT_PATH="$_tabry_imports_path"
   fi
 
   [[ -x "$_tabry_executable" ]] || { echo "tabry_bash.sh: error: can't find tabry executable at $_tabry_executable -- if you are using the script from source rather than using via 'tabry bash', perhaps you need to run 'cargo build'?"; return 1; }
   local oldifs="$IFS"
   IFS=$'\n'
   for cmd in $("$_tabry_executable" commands); do
-      complete -F _tabry_completions_UNIQ_FN_ID $cmd
+      _tabry_complete_one_command_UNIQ_FN_ID $cmd
   done
   IFS="$oldifs"
 }
 
 _tabry_complete_one_command_UNIQ_FN_ID() {
-  complete -F _tabry_completions_UNIQ_FN_ID $1
+  compdef _tabry_completions_UNIQ_FN_ID $1
 }
 
 _tabry_completions_UNIQ_FN_ID() {
   _tabry_completions_internal_UNIQ_FN_ID "$_tabry_executable"
 }
 
-_tabry_set_compreply_from_lines_UNIQ_FN_ID() {
-  # Feed in lines from a variable, quoting each line.
-  # Using readarray is much faster than using += many times to build the array.
+# Add lines from a variable (already escaped by tabry complete --escape) as candidates, skipping
+# empty ones. tabry already gives options in the order they should be shown, so they go in an
+# unsorted group (-V); and it has already filtered them, so zsh shouldn't match them again (-U).
+_tabry_add_lines_UNIQ_FN_ID() {
   local lines="$1"
-  local saveifs="$IFS"
+  shift
+  local -a candidates=("${(@f)lines}")
+  candidates=(${candidates:#})
+  (( ${#candidates} )) && compadd -V tabry -Q -U "$@" -a candidates
+}
 
-  COMPREPLY=("${(@f)$(
-    IFS=$'\n'
-    while IFS= read -r line; do
-      if [[ -n "$line" ]]; then
-        printf '%q\n' "$line"
-      fi
-    done <<< "$lines"
-    IFS="$saveifs"
-  )}")
+# Complete using another command's own completion, for "opts delegate". The argument is the
+# tab-separated command line to complete, as computed by tabry (the delegate command plus any
+# args being forwarded); its last word is the one being completed. Unlike the bash version,
+# this uses zsh's own completion system, so it works for commands with native zsh completion.
+_tabry_delegate_UNIQ_FN_ID() {
+  local -a delegate_words=("${(@ps:\t:)1}")
+
+  if [[ ${#delegate_words} -le 1 ]]; then
+    # completing the command name itself
+    _command_names
+    return
+  fi
+
+  # words and CURRENT are the completion system's idea of the command line; _normal completes
+  # them with whatever completion is defined for the first word. Both are restored afterwards.
+  local -a tabry_words=("${words[@]}")
+  local tabry_current="$CURRENT"
+  words=("${delegate_words[@]}")
+  CURRENT=${#words}
+  _normal
+  words=("${tabry_words[@]}")
+  CURRENT="$tabry_current"
 }
 
 # This is unchanged from ruby tabry, except to remove the second arg
@@ -55,102 +70,58 @@ _tabry_completions_internal_UNIQ_FN_ID()
   local tabry_bash_executable="$1"
 
   [[ -n "$TABRY_DEBUG" ]] && echo && echo -n tabry start bash: && date +%s.%N >&2
-  local saveifs="$IFS"
-  IFS=$'\n'
 
-  [[ -n "$TABRY_DEBUG" ]] && printf "%q %q %q %q\n" "$tabry_bash_executable" complete "$COMP_LINE" "$COMP_POINT"
-  local result=$("$tabry_bash_executable" complete "$COMP_LINE" "$COMP_POINT")
+  # Use the words as zsh split them ($words is 1-indexed, and has the words as typed). Only the
+  # part of the current word before the cursor is completed: $SUFFIX is the part after it (with
+  # the complete_in_word option; otherwise zsh completes at the end of the word).
+  local cur="${words[CURRENT][1,-$(( ${#SUFFIX} + 1 ))]}"
+  [[ -n "$TABRY_DEBUG" ]] && printf "%q " "$tabry_bash_executable" complete-words --escape --quoted --cword $((CURRENT - 1)) --cur "$cur" -- "${words[@]}" && echo
+  local result=$(printf '%s\0' "${words[@]}" | "$tabry_bash_executable" complete-words --escape --quoted --stdin --cword $((CURRENT - 1)) --cur "$cur")
   local specials
   local specials_line
+  # compadd options for the candidates, e.g. no space after them
+  local -a compadd_opts=()
 
   if [[ $result == *$'\n'$'\n'* ]]; then
     # double newline signals use of specials (file, directory)
     # Warning: fragile code ahead.
     # Split on double-newline to get regular options and specials.
     specials="$(echo "$result"|sed '1,/^$/d')"
-    result="$(echo "$result)"|sed '/^$/q')"
+    result="$(echo "$result"|sed '/^$/q')"
+
+    # the nospace special applies to the regular options, so look for it before adding them
+    [[ $'\n'"$specials"$'\n' == *$'\n'nospace$'\n'* ]] && compadd_opts=(-S '')
 
     # First, add anything before the double newline in (regular options)
-    _tabry_set_compreply_from_lines_UNIQ_FN_ID "$result"
+    _tabry_add_lines_UNIQ_FN_ID "$result" "${compadd_opts[@]}"
 
     while IFS= read -r specials_line; do
       if [[ "$specials_line" == "file" ]]; then
         # File special
-        # doesn't seem to be a "plusfiles" like there is for "plusdirs"
-        # TODO check if we need to shellescape
-        COMPREPLY+=($(
-          compgen -A file "${COMP_WORDS[$COMP_CWORD]}" | while IFS=$'\n' read filename; do
-          printf "%q\n" "$filename"
-        done
-        ))
+        _files
       elif [[ "$specials_line" == "dir" ]]; then
-        # Directory special
-        # If there are only directory results, use nospace to not add a space after it,
-        # like "cd" tab completion does.
-        [[ ${#COMPREPLY[@]} -eq 0 ]] && compopt -o nospace
-        compopt -o plusdirs
-      elif [[ "$specials" == "description_if_optionless" ]]; then
-        # "description_if_optionless" special: Options are are meant to be description or examples
-        # and not actual options. Add an empty option so we won't tab complete.
-        compopt -o nosort
-        COMPREPLY+=('')
-      elif [[ "$specials_line" == "delegate "* ]]; then
-        local delegate_cmd="${specials_line#delegate }"
-
-        # split delegate_cmd to get the actual command (first word):
-        # this is not reliable but will work for now...
-        local delegate_cmd_arg0="${delegate_cmd%% *}"
-        local complete_fn=$(complete -p "$delegate_cmd_arg0" 2>/dev/null | sed 's/.*-F \([^ ]*\) .*/\1/')
-        if [[ -z "$complete_fn" ]]; then
-          _completion_loader "$delegate_cmd_arg0"
-        fi
-        complete_fn=$(complete -p "$delegate_cmd_arg0" 2>/dev/null | sed 's/.*-F \([^ ]*\) .*/\1/')
-        if [[ -z "$complete_fn" ]]; then
-          echo "Error: Could not find completion function for $delegate_cmd_arg0" >&2
-          return 1
-        fi
-
-        # Backup
-        local comp_cword="$COMP_CWORD"
-        local comp_point="$COMP_POINT"
-        local comp_line="$COMP_LINE"
-        local comp_words=("${COMP_WORDS[@]}")
-        local comp_key="$COMP_KEY"
-        local comp_type="$COMP_TYPE"
-        local comp_reply=("${COMPREPLY[@]}")
-
-        # get completions -> COMPREPLY
-        IFS="$saveifs"
-        COMP_LINE="$delegate_cmd"
-        COMP_POINT="${#delegate_cmd}"
-        #__compal__split_cmd_line "$COMP_LINE"
-        COMP_WORDS=("${__compal__retval[@]}" "")
-        COMP_WORDS=($delegate_cmd "")
-        COMP_CWORD=$((${#COMP_WORDS[@]} - 1))
-        COMPREPLY=()
-        "$complete_fn"
-
-        # Reset the completion variables
-        IFS=$'\n'
-        COMP_CWORD="$comp_cword"
-        COMP_POINT="$comp_point"
-        COMP_LINE="$comp_line"
-        COMP_WORDS=("${comp_words[@]}")
-        COMP_KEY="$comp_key"
-        COMP_TYPE="$comp_type"
-
-        # Concatenate the completions
-        COMPREPLY=("${comp_reply[@]}" "${COMPREPLY[@]}")
+        # Directory special. _files adds a / and no space after directories, like "cd" does.
+        _files -/
+      elif [[ "$specials_line" == "description_if_optionless"$'\t'* ]]; then
+        # "description_if_optionless" special: the arg has no options, so show what is expected
+        # instead. Fields (name, title, description) are tab-separated and may be empty.
+        local hint_rest="${specials_line#description_if_optionless$'\t'}"
+        local hint_name="${hint_rest%%$'\t'*}"
+        hint_rest="${hint_rest#*$'\t'}"
+        local hint_title="${hint_rest%%$'\t'*}"
+        local hint_desc="${hint_rest#*$'\t'}"
+        local hint="<${hint_title:-$hint_name}>${hint_desc:+ $hint_desc}"
+        _message -r "$hint"
+      elif [[ "$specials_line" == "command" ]]; then
+        # Command special: the name of a command to run (opts command)
+        _command_names
+      elif [[ "$specials_line" == "delegate_argv"$'\t'* ]]; then
+        _tabry_delegate_UNIQ_FN_ID "${specials_line#delegate_argv$'\t'}"
       fi
     done <<< "$specials"
   else
-    _tabry_set_compreply_from_lines_UNIQ_FN_ID "$result"
-    COMPREPLY=()
-    while IFS= read -r line; do
-      COMPREPLY+=($(printf "%q" "$line"))
-    done <<< "$result"
+    _tabry_add_lines_UNIQ_FN_ID "$result"
   fi
 
-  IFS="$saveifs"
   [[ -n "$TABRY_DEBUG" ]] && echo -n tabry end bash: && date +%s.%N >&2
 }
@@ -0,0 +1,106 @@
+/// User-level command aliases, for commands run under another name (`alias k=kubectl`) or with
+/// leading args (`alias kp='kubectl --context prod'`), so they can use the config of the command
+/// they run without duplicating it.
+///
+/// The file is $TABRY_ALIASES_FILE, or $XDG_CONFIG_HOME/tabry/aliases
+/// (~/.config/tabry/aliases by default), with one alias per line:
+///
+/// ```text
+/// # comment
+/// k = kubectl
+/// kp = kubectl --context prod
+/// ```
+///
+/// The right-hand side is split like a shell would; its first word is the command whose config
+/// is used and the rest are tokens put before the ones typed.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAlias {
+    pub name: String,
+    pub command: String,
+    pub leading_args: Vec<String>,
+}
+
+pub fn aliases_file() -> Option<PathBuf> {
+    if let Ok(file) = std::env::var("TABRY_ALIASES_FILE") {
+        return Some(PathBuf::from(file));
+    }
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+    };
+    Some(config_dir.join("tabry/aliases"))
+}
+
+/// Parse the text of an aliases file. Lines which aren't valid aliases are ignored: the file is
+/// read on every completion, which is no place to report errors.
+pub fn parse_aliases(text: &str) -> Vec<UserAlias> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.starts_with('#') {
+                return None;
+            }
+            let (name, value) = line.split_once('=')?;
+            let name = name.trim();
+            let mut words = shell_words::split(value).ok()?.into_iter();
+            let command = words.next()?;
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            Some(UserAlias {
+                name: name.to_owned(),
+                command,
+                leading_args: words.collect(),
+            })
+        })
+        .collect()
+}
+
+pub fn load_aliases(path: &Path) -> Vec<UserAlias> {
+    fs::read_to_string(path)
+        .map(|text| parse_aliases(&text))
+        .unwrap_or_default()
+}
+
+/// Aliases from the user's aliases file, if there is one.
+pub fn user_aliases() -> Vec<UserAlias> {
+    aliases_file()
+        .map(|path| load_aliases(&path))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aliases() {
+        let aliases = parse_aliases(
+            "# kubernetes\n\
+             k = kubectl\n\
+             kp=kubectl --context 'prod cluster'\n\
+             \n\
+             not an alias\n\
+             bad name = kubectl\n\
+             empty =\n",
+        );
+        assert_eq!(
+            aliases,
+            [
+                UserAlias {
+                    name: "k".to_owned(),
+                    command: "kubectl".to_owned(),
+                    leading_args: vec![],
+                },
+                UserAlias {
+                    name: "kp".to_owned(),
+                    command: "kubectl".to_owned(),
+                    leading_args: vec!["--context".to_owned(), "prod cluster".to_owned()],
+                },
+            ]
+        );
+    }
+}
@@ -1,12 +1,31 @@
-/// Given a "foo.tabry" file, checks if there is a compiled version
-/// under the name "foo.tabry.cachejson", and it is _newer_ than
+/// Given a "foo.tabry" file, checks if there is a compiled version of it in $XDG_CACHE_HOME/tabry
+/// (~/.cache/tabry by default), under a name made from a hash of its path, and it is _newer_ than
 /// the tabry file. If there is, uses that as the tabry config; if there isn't, kicks off the
-/// compiler (in the future I'd like to have the compiler in rust) and then uses it for completion.
-/// (this could be done in shell but it would add a bit of time to run every tab completion)
+/// compiler and then uses it for completion. (this could be done in shell but it would add a bit
+/// of time to run every tab completion) The cache is never written next to the config, which may
+/// be in a dir the user can't write to (like /usr/share/tabry).
+///
+/// If TABRY_LINK is set, the cached config is also linked (see `TabryConf::link`), so includes
+/// are checked when the file is compiled, not followed on every completion. Linked configs are
+/// cached under a different name ("<hash>.linked.cachejson") so toggling the setting never
+/// picks up a stale cache file.
+///
+/// A config which `extends` the next one found for the command (see `TabryConf::merge`) is merged
+/// with it, and the result cached in the same way, under a name made from a hash of the paths of
+/// all the files, so it is recompiled if any of them change.
+///
+/// The aliases each config declares (`cmd name,alias`) are kept in an index in the same dir
+/// ("cmd_aliases.json"), so finding the config for an alias doesn't mean loading every config.
+/// A config is only loaded again when its file changes.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use thiserror::Error;
 
+use crate::core::config::TabryConf;
+
 #[derive(Error, Debug)]
 pub enum TabryCacheError {
     #[error("error compiling tabry file -- IO error: {0}")]
@@ -15,6 +34,10 @@ pub enum TabryCacheError {
     Compile(#[from] crate::lang::LangError),
     #[error("error compiling tabry file -- JSON serialization error: {0}")]
     JSONSerialization(#[from] serde_json::Error),
+    #[error("error linking tabry file: {0}")]
+    Link(#[from] crate::core::config::TabryConfError),
+    #[error("error loading config file {0}: {1}")]
+    Load(String, crate::core::config::TabryConfError),
 }
 
 fn modtime(filename: &str) -> Option<SystemTime> {
@@ -26,22 +49,263 @@ pub fn resolve_and_compile_cache_file(filename: &str) -> Result<String, TabryCac
     if filename.ends_with(".json") {
         return Ok(filename.to_owned());
     }
+    compile_cache_file_in(&cache_dir(), &[filename.to_owned()])
+}
 
-    let mut cache_filename = filename.to_string();
-    cache_filename.push_str(".cachejson");
+fn load_unlinked(filename: &str) -> Result<TabryConf, TabryCacheError> {
+    if filename.ends_with(".json") {
+        TabryConf::from_file(filename).map_err(|e| TabryCacheError::Load(filename.to_owned(), e))
+    } else {
+        Ok(crate::lang::compile(&fs::read_to_string(filename)?)?)
+    }
+}
 
-    let cache_modtime = modtime(&cache_filename);
-    let tabry_modtime = modtime(filename);
+fn cache_dir() -> PathBuf {
+    let cache_home = match (std::env::var("XDG_CACHE_HOME"), std::env::var("HOME")) {
+        (Ok(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Ok(home)) if !home.is_empty() => PathBuf::from(home).join(".cache"),
+        _ => std::env::temp_dir(),
+    };
+    cache_home.join("tabry")
+}
+
+/// Merge the first config with the ones it extends (given all the configs found for a command,
+/// in order of precedence).
+fn load_and_merge(filenames: &[String]) -> Result<TabryConf, TabryCacheError> {
+    let mut configs = vec![];
+    for filename in filenames {
+        let config = load_unlinked(filename)?;
+        let extends = config.extends;
+        configs.push(config);
+        if !extends {
+            break;
+        }
+    }
+    let mut merged = configs.pop().expect("at least one config file");
+    while let Some(overlay) = configs.pop() {
+        merged = merged.merge(overlay);
+    }
+    Ok(merged)
+}
+
+/// 64-bit FNV-1a hash of the paths. Unlike `DefaultHasher`, it doesn't change between Rust
+/// releases, so cache file names made from it stay the same when tabry is rebuilt.
+fn hash_paths(filenames: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for filename in filenames {
+        // NUL separates the paths, as it can't appear in one
+        for byte in filename.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Like `resolve_and_compile_cache_file`, but given all the config files found for a command (in
+/// order of precedence), so a config that `extends` the next one can be merged with it.
+pub fn resolve_and_compile_merged_cache_file(
+    filenames: &[String],
+) -> Result<String, TabryCacheError> {
+    if filenames.len() == 1 {
+        return resolve_and_compile_cache_file(&filenames[0]);
+    }
+
+    compile_cache_file_in(&cache_dir(), filenames)
+}
 
-    // if needs to be recompiled:
-    if cache_modtime.is_none() || cache_modtime < tabry_modtime {
-        let tabry_file = fs::read_to_string(filename)?;
-        let compiled = crate::lang::compile(&tabry_file);
-        let json = serde_json::to_string(&compiled?)?;
-        fs::write(&cache_filename, json)?;
-        // TODO ideally, shouldn't bother reading and decoding the JSON file since we alredy have the
-        // compiled form in memory
+/// Compile (and merge) the config files into a file in `cache_dir`, unless it is already there
+/// and newer than all of them, returning the name of the file.
+fn compile_cache_file_in(
+    cache_dir: &Path,
+    filenames: &[String],
+) -> Result<String, TabryCacheError> {
+    let link = crate::core::util::env_flag("TABRY_LINK");
+    let cache_filename = cache_dir
+        .join(format!(
+            "{:016x}.{}",
+            hash_paths(filenames),
+            if link {
+                "linked.cachejson"
+            } else {
+                "cachejson"
+            }
+        ))
+        .to_string_lossy()
+        .into_owned();
+
+    let cache_modtime = modtime(&cache_filename);
+    if cache_modtime.is_none() || filenames.iter().any(|f| cache_modtime < modtime(f)) {
+        let mut merged = load_and_merge(filenames)?;
+        if link {
+            merged = merged.link()?;
+        }
+        write_cache_file(Path::new(&cache_filename), &serde_json::to_string(&merged)?)?;
     }
 
     Ok(cache_filename)
 }
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CmdAliasesIndex {
+    /// config file -> aliases declared in it, as of when the file was last modified
+    entries: HashMap<String, CmdAliasesEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CmdAliasesEntry {
+    modified: SystemTime,
+    #[serde(flatten)]
+    aliases: ConfigAliases,
+}
+
+/// The aliases a config declares, and whether it extends the next config for the command (whose
+/// aliases then apply too).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigAliases {
+    pub aliases: Vec<String>,
+    pub extends: bool,
+}
+
+/// Write a file in the cache dir via a temporary file, so other processes never read a partly
+/// written file.
+fn write_cache_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp_path, contents)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })
+}
+
+/// The aliases declared (with `cmd name,alias`) in each of the config files, using the index in
+/// the cache for files which haven't changed. A config which fails to load has no aliases.
+pub fn cmd_aliases(filenames: &[String]) -> Vec<ConfigAliases> {
+    cmd_aliases_with_index(&cache_dir().join("cmd_aliases.json"), filenames)
+}
+
+fn cmd_aliases_with_index(index_filename: &Path, filenames: &[String]) -> Vec<ConfigAliases> {
+    let mut index: CmdAliasesIndex = fs::read_to_string(index_filename)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+
+    let mut changed = false;
+    let aliases = filenames
+        .iter()
+        .map(|filename| {
+            let modified = modtime(filename);
+            match index.entries.get(filename) {
+                Some(entry) if Some(entry.modified) == modified => entry.aliases.clone(),
+                _ => {
+                    let aliases = load_unlinked(filename)
+                        .map(|config| ConfigAliases {
+                            aliases: config.cmd_aliases,
+                            extends: config.extends,
+                        })
+                        .unwrap_or_default();
+                    if let Some(modified) = modified {
+                        let entry = CmdAliasesEntry {
+                            modified,
+                            aliases: aliases.clone(),
+                        };
+                        index.entries.insert(filename.clone(), entry);
+                        changed = true;
+                    }
+                    aliases
+                }
+            }
+        })
+        .collect();
+
+    if changed {
+        // Only a cache, so completion carries on without it
+        let json = serde_json::to_string(&index).unwrap_or_default();
+        if let Err(e) = write_cache_file(index_filename, &json) {
+            tracing::warn!("can't write {}: {}", index_filename.display(), e);
+        }
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_caches_compiled_config_outside_a_read_only_config_dir() {
+        let dir = std::env::temp_dir().join(format!("tabry-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config_dir = dir.join("share/tabry");
+        let cache_dir = dir.join("cache");
+        fs::create_dir_all(&config_dir).unwrap();
+        let config = config_dir.join("foo.tabry").to_string_lossy().into_owned();
+        fs::write(&config, "cmd foo\nsub bar").unwrap();
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let result = compile_cache_file_in(&cache_dir, &[config]);
+        let in_config_dir = fs::read_dir(&config_dir).unwrap().count();
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let cache_filename = result.unwrap();
+        let compiled = TabryConf::from_file(&cache_filename).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(cache_filename.starts_with(cache_dir.to_str().unwrap()));
+        assert_eq!(in_config_dir, 1);
+        assert_eq!(compiled.cmd.as_deref(), Some("foo"));
+    }
+
+    #[test]
+    fn test_hash_paths() {
+        assert_eq!(hash_paths(&[]), 0xcbf29ce484222325);
+        // FNV-1a of "a\0b\0"
+        assert_eq!(
+            hash_paths(&["a".to_owned(), "b".to_owned()]),
+            0xab40d7820d408076
+        );
+        assert_ne!(
+            hash_paths(&["ab".to_owned()]),
+            hash_paths(&["a".to_owned(), "b".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_cmd_aliases_index() {
+        let dir = std::env::temp_dir().join(format!("tabry-cmd-aliases-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let index_filename = dir.join("cmd_aliases.json");
+        let config = dir.join("mytool.tabry").to_string_lossy().into_owned();
+        let broken = dir.join("broken.tabry").to_string_lossy().into_owned();
+        fs::write(&config, "cmd mytool,mt\nsub build").unwrap();
+        fs::write(&broken, "sub {").unwrap();
+        let filenames = [config.clone(), broken];
+
+        let aliases = cmd_aliases_with_index(&index_filename, &filenames);
+        let mt = ConfigAliases {
+            aliases: vec!["mt".to_owned()],
+            extends: false,
+        };
+        assert_eq!(aliases, [mt, ConfigAliases::default()]);
+        assert!(fs::read_to_string(&index_filename)
+            .unwrap()
+            .contains("\"mt\""));
+
+        // An entry for an unchanged file is used as-is
+        let index = fs::read_to_string(&index_filename)
+            .unwrap()
+            .replace("\"mt\"", "\"from-index\"");
+        fs::write(&index_filename, index).unwrap();
+        let aliases = cmd_aliases_with_index(&index_filename, &filenames);
+        fs::remove_dir_all(&dir).unwrap();
+        let from_index = ConfigAliases {
+            aliases: vec!["from-index".to_owned()],
+            extends: false,
+        };
+        assert_eq!(aliases, [from_index, ConfigAliases::default()]);
+    }
+}
@@ -0,0 +1,214 @@
+/// Generate a tabry config from a clap command definition, so tabry can complete itself (and
+/// never get out of sync with its CLI) without shipping a tabry file.
+///
+/// Positional args become args and options become flags. Options are offered from possible
+/// values and value hints: file and dir hints become `opts file`/`opts dir`, a command line
+/// (`ValueHint::CommandWithArguments`) becomes `opts command`, and a command name
+/// (`ValueHint::CommandName`) is taken to mean a command with a tabry config, offered from
+/// `tabry commands` run with the current executable (which needn't be on `$PATH`).
+///
+/// In tabry, flags of a sub can also be given after its subcommands, so a command's flags are
+/// offered after its subcommands even if they aren't `global` in clap.
+use clap::{builder::ValueHint, Arg, ArgAction, Command};
+use std::collections::HashMap;
+
+use crate::core::{config::TabryConf, types::*};
+
+/// First line of a help string, which is what shells have room for.
+fn summary(help: Option<&clap::builder::StyledStr>) -> Option<String> {
+    let help = help?.to_string();
+    let line = help.lines().next()?.trim();
+    (!line.is_empty()).then(|| line.to_owned())
+}
+
+fn arg_options(arg: &Arg) -> Vec<TabryOpt> {
+    let mut options: Vec<TabryOpt> = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| TabryOpt::Const {
+            value: value.get_name().to_owned(),
+            case_insensitive: false,
+        })
+        .collect();
+    match arg.get_value_hint() {
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::ExecutablePath => {
+            options.push(TabryOpt::File)
+        }
+        ValueHint::DirPath => options.push(TabryOpt::Dir),
+        ValueHint::CommandWithArguments => options.push(TabryOpt::Command),
+        ValueHint::CommandName => options.push(TabryOpt::Shell {
+            value: format!("{} commands", super::escaped_exe()),
+            case_insensitive: false,
+            prefix_aware: false,
+        }),
+        _ => {}
+    }
+    options
+}
+
+fn positional_arg(arg: &Arg) -> TabryArg {
+    let varargs = matches!(arg.get_action(), ArgAction::Append)
+        || arg.get_num_args().is_some_and(|n| n.max_values() > 1);
+    TabryArg::TabryConcreteArg(TabryConcreteArg {
+        name: Some(arg.get_id().to_string()),
+        title: None,
+        description: summary(arg.get_help()),
+        options: arg_options(arg),
+        optional: !arg.is_required_set(),
+        varargs,
+    })
+}
+
+fn flag(arg: &Arg) -> Option<TabryFlag> {
+    let short = arg.get_short().map(|c| c.to_string());
+    let mut names = arg.get_long().map(str::to_owned).into_iter().chain(short);
+    let name = names.next()?;
+    let mut aliases: Vec<String> = names.collect();
+    aliases.extend(
+        arg.get_visible_aliases()
+            .into_iter()
+            .flatten()
+            .map(str::to_owned),
+    );
+    aliases.extend(
+        arg.get_visible_short_aliases()
+            .into_iter()
+            .flatten()
+            .map(|c| c.to_string()),
+    );
+    Some(TabryFlag::TabryConcreteFlag(TabryConcreteFlag {
+        name,
+        aliases,
+        options: arg_options(arg),
+        description: summary(arg.get_help()),
+        arg: arg.get_action().takes_values(),
+        required: arg.is_required_set(),
+        repeatable: matches!(arg.get_action(), ArgAction::Append | ArgAction::Count),
+        negatable: false,
+    }))
+}
+
+fn sub(command: &Command) -> TabryConcreteSub {
+    let mut args = vec![];
+    let mut flags = vec![];
+    // build() leaves --version on subcommands even when they don't accept it
+    let takes_arg = |arg: &&Arg| {
+        !arg.is_hide_set() && (arg.get_id() != "version" || command.get_version().is_some())
+    };
+    for arg in command.get_arguments().filter(takes_arg) {
+        if arg.is_positional() {
+            args.push(positional_arg(arg));
+        } else {
+            flags.extend(flag(arg));
+        }
+    }
+    let subs = command
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .map(|s| TabrySub::TabryConcreteSub(self::sub(s)))
+        .collect();
+    TabryConcreteSub {
+        name: Some(command.get_name().to_owned()),
+        aliases: command.get_visible_aliases().map(str::to_owned).collect(),
+        description: summary(command.get_about()),
+        args,
+        flags,
+        subs,
+        exclusive_flags: vec![],
+        flag_requirements: vec![],
+        settings: TabrySettings::default(),
+    }
+}
+
+pub fn config_from_clap(mut command: Command) -> TabryConf {
+    // adds --help, --version and the help subcommand, and fills in defaults (e.g. num_args)
+    command.build();
+    TabryConf {
+        cmd: Some(command.get_name().to_owned()),
+        cmd_aliases: vec![],
+        extends: false,
+        main: TabryConcreteSub {
+            name: None,
+            ..sub(&command)
+        },
+        arg_includes: HashMap::new(),
+        option_includes: HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{machine::Machine, options_finder::OptionsFinder};
+
+    fn example_command() -> Command {
+        Command::new("prog")
+            .version("1.0")
+            .about("An example\nwith more details")
+            .arg(
+                Arg::new("verbose")
+                    .long("verbose")
+                    .short('v')
+                    .action(ArgAction::Count)
+                    .help("More output"),
+            )
+            .subcommand(
+                Command::new("open")
+                    .visible_alias("o")
+                    .arg(
+                        Arg::new("format")
+                            .long("format")
+                            .value_parser(["json", "yaml"]),
+                    )
+                    .arg(
+                        Arg::new("path")
+                            .value_hint(ValueHint::FilePath)
+                            .required(true),
+                    ),
+            )
+            .subcommand(Command::new("secret").hide(true))
+    }
+
+    fn completions(config: &TabryConf, tokens: &[&str], last_token: &str) -> Vec<String> {
+        let tokens: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+        let result = Machine::run(config.clone(), &tokens).unwrap();
+        let options = OptionsFinder::new(result, false)
+            .options(last_token)
+            .unwrap();
+        options.options.into_iter().map(|opt| opt.value).collect()
+    }
+
+    #[test]
+    fn test_config_from_clap() {
+        let config = config_from_clap(example_command());
+        assert_eq!(config.cmd.as_deref(), Some("prog"));
+        assert_eq!(config.main.description.as_deref(), Some("An example"));
+
+        let open = config.dig_sub(&vec!["open".to_owned()]).unwrap();
+        assert_eq!(open.aliases, ["o"]);
+        let path = config.expand_args(&open.args).next().unwrap();
+        assert!(!path.optional && !path.varargs);
+        assert!(matches!(path.options[..], [TabryOpt::File]));
+
+        let verbose = config.expand_flags(&config.main.flags).next().unwrap();
+        assert_eq!(verbose.name, "verbose");
+        assert_eq!(verbose.aliases, ["v"]);
+        assert!(verbose.repeatable && !verbose.arg);
+
+        assert_eq!(completions(&config, &[], ""), ["open", "help"]);
+        assert_eq!(
+            completions(&config, &[], "--"),
+            ["--verbose", "--help", "--version"]
+        );
+        // flags of parent subs are inherited in tabry
+        assert_eq!(
+            completions(&config, &["open"], "--"),
+            ["--format", "--help", "--verbose", "--version"]
+        );
+        assert_eq!(
+            completions(&config, &["o", "--format"], ""),
+            ["json", "yaml"]
+        );
+    }
+}
@@ -1,67 +1,171 @@
-// find *.tabry of *.json for command in TABRY_CONFIG_PATH
+// find *.tabry or *.json for a command in the config dirs, in order of precedence:
+// 1. project-local `.tabry/` dirs, found by walking up from the current directory (nearest first;
+//    only if TABRY_PROJECT_CONFIGS is set, as they may come from any repository checked out)
+// 2. TABRY_IMPORT_PATH (colon-separated)
+// 3. $XDG_DATA_HOME/tabry (~/.local/share/tabry by default)
+// 4. $XDG_DATA_DIRS/*/tabry (/usr/local/share/tabry and /usr/share/tabry by default), for
+//    system packages
 
 use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 const EXTENSIONS: [&str; 2] = [".tabry", ".json"];
 
 #[derive(Error, Debug)]
-#[error("config for {0} cannot be found in TABRY_IMPORT_PATH ({1})")]
+#[error("config for {0} cannot be found in any of: {1}")]
 pub struct ConfigFinderError(String, String);
 
-pub fn import_path() -> String {
-    match std::env::var("TABRY_IMPORT_PATH")
-        .ok()
-        .filter(|t| !t.is_empty())
-    {
-        Some(s) => s,
-        None => "./".to_owned(),
+/// Where a config dir comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Project,
+    ImportPath,
+    XdgDataHome,
+    XdgDataDirs,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Project => "project",
+            ConfigSource::ImportPath => "TABRY_IMPORT_PATH",
+            ConfigSource::XdgDataHome => "XDG_DATA_HOME",
+            ConfigSource::XdgDataDirs => "XDG_DATA_DIRS",
+        })
     }
 }
 
-fn expand_tilde_to_home(path: &str) -> Cow<'_, str> {
-    if path.starts_with("~/") {
-        let home = std::env::var("HOME").unwrap();
-        Cow::Owned(format!("{}{}", home, &path[1..]))
-    } else {
-        Cow::Borrowed(path)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDir {
+    pub path: PathBuf,
+    pub source: ConfigSource,
+}
+
+/// A config file found for a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundConfig {
+    pub path: PathBuf,
+    pub source: ConfigSource,
+}
+
+fn expand_tilde_to_home<'a>(path: &'a str, home: Option<&str>) -> Cow<'a, str> {
+    match (path.strip_prefix("~/"), home) {
+        (Some(rest), Some(home)) => Cow::Owned(format!("{}/{}", home, rest)),
+        _ => Cow::Borrowed(path),
     }
 }
 
-pub fn find_tabry_config(command_name: &str) -> Result<String, ConfigFinderError> {
-    for import_dir in import_path().split(':').map(expand_tilde_to_home) {
-        for ext in &EXTENSIONS {
-            let mut path = std::path::PathBuf::from(import_dir.as_ref());
-            path.push(format!("{}{}", command_name, ext));
-            let path = path.to_str().unwrap();
-            // if exists:
-            if std::path::Path::new(path).exists() {
-                return Ok(path.to_string());
+/// Config dirs in order of precedence, given a way to look up environment variables and the
+/// current directory (if known).
+fn config_dirs_with(var: impl Fn(&str) -> Option<String>, cwd: Option<&Path>) -> Vec<ConfigDir> {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let home = var("HOME");
+    let mut dirs = vec![];
+    let mut push = |path: PathBuf, source: ConfigSource| {
+        if !dirs.iter().any(|d: &ConfigDir| d.path == path) {
+            dirs.push(ConfigDir { path, source });
+        }
+    };
+
+    let project_configs = var("TABRY_PROJECT_CONFIGS").is_some_and(|v| v != "0" && v != "false");
+    if let (Some(cwd), true) = (cwd, project_configs) {
+        for dir in cwd.ancestors() {
+            let project_dir = dir.join(".tabry");
+            if project_dir.is_dir() {
+                push(project_dir, ConfigSource::Project);
             }
         }
     }
 
-    Err(ConfigFinderError(command_name.to_owned(), import_path()))
+    if let Some(import_path) = var("TABRY_IMPORT_PATH") {
+        for dir in import_path.split(':').filter(|dir| !dir.is_empty()) {
+            let dir = expand_tilde_to_home(dir, home.as_deref());
+            push(PathBuf::from(dir.as_ref()), ConfigSource::ImportPath);
+        }
+    }
+
+    let data_home = match (var("XDG_DATA_HOME"), &home) {
+        (Some(data_home), _) => Some(PathBuf::from(data_home)),
+        (None, Some(home)) => Some(Path::new(home).join(".local/share")),
+        (None, None) => None,
+    };
+    if let Some(data_home) = data_home {
+        push(data_home.join("tabry"), ConfigSource::XdgDataHome);
+    }
+
+    let data_dirs = var("XDG_DATA_DIRS").unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    for dir in data_dirs.split(':').filter(|dir| !dir.is_empty()) {
+        push(Path::new(dir).join("tabry"), ConfigSource::XdgDataDirs);
+    }
+
+    dirs
+}
+
+pub fn config_dirs() -> Vec<ConfigDir> {
+    let cwd = std::env::current_dir().ok();
+    config_dirs_with(|name| std::env::var(name).ok(), cwd.as_deref())
 }
 
-pub fn all_supported_commands() -> Result<Vec<String>, std::io::Error> {
+fn configs_in_dirs(dirs: &[ConfigDir], command_name: &str) -> Vec<FoundConfig> {
     let mut res = vec![];
-    for import_dir in import_path().split(':') {
-        let read_dir = std::fs::read_dir(import_dir);
-        if read_dir.is_err() {
-            continue;
+    for dir in dirs {
+        for ext in &EXTENSIONS {
+            let path = dir.path.join(format!("{}{}", command_name, ext));
+            if path.exists() {
+                res.push(FoundConfig {
+                    path,
+                    source: dir.source,
+                });
+            }
         }
-        for entry in read_dir? {
-            let path = entry?.path();
-            let path = path.to_str();
-            let path = match path {
-                Some(path) => path,
-                None => continue,
+    }
+    res
+}
+
+/// All config files for a command, in order of precedence: the first is the one used, and it
+/// shadows the rest.
+pub fn find_all_tabry_configs(command_name: &str) -> Vec<FoundConfig> {
+    configs_in_dirs(&config_dirs(), command_name)
+}
+
+fn not_found(command_name: &str, dirs: &[ConfigDir]) -> ConfigFinderError {
+    let searched: Vec<_> = dirs.iter().map(|d| d.path.to_string_lossy()).collect();
+    ConfigFinderError(command_name.to_owned(), searched.join(":"))
+}
+
+/// The error for a command without a config, listing the dirs searched.
+pub fn config_not_found(command_name: &str) -> ConfigFinderError {
+    not_found(command_name, &config_dirs())
+}
+
+/// The config file used for each command with one (i.e. not including shadowed files), with
+/// the command name.
+fn configs_for_all_commands(
+    dirs: &[ConfigDir],
+) -> Result<Vec<(String, FoundConfig)>, std::io::Error> {
+    let mut res: Vec<(String, FoundConfig)> = vec![];
+    for dir in dirs {
+        let Ok(read_dir) = std::fs::read_dir(&dir.path) else {
+            continue;
+        };
+        let mut paths = read_dir
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // .tabry before .json, as for find_all_tabry_configs
+        paths.sort_by_key(|path| path.extension().is_some_and(|ext| ext == "json"));
+        for path in paths {
+            let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
+                continue;
             };
             for ext in &EXTENSIONS {
-                if path.ends_with(ext) {
-                    let cmd = path[0..path.len() - ext.len()].split('/').last();
-                    if let Some(cmd) = cmd {
-                        res.push(cmd.to_owned());
+                if let Some(cmd) = file_name.strip_suffix(ext) {
+                    if !res.iter().any(|(c, _)| c == cmd) {
+                        let found = FoundConfig {
+                            path: path.clone(),
+                            source: dir.source,
+                        };
+                        res.push((cmd.to_owned(), found));
                     }
                 }
             }
@@ -69,3 +173,157 @@ pub fn all_supported_commands() -> Result<Vec<String>, std::io::Error> {
     }
     Ok(res)
 }
+
+pub fn all_configs() -> Result<Vec<(String, FoundConfig)>, std::io::Error> {
+    configs_for_all_commands(&config_dirs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "tabry-config-finder-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| map.get(name).cloned()
+    }
+
+    fn paths_and_sources(dirs: &[ConfigDir]) -> Vec<(String, ConfigSource)> {
+        dirs.iter()
+            .map(|d| (d.path.to_string_lossy().into_owned(), d.source))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_dirs_defaults() {
+        let dirs = config_dirs_with(vars(&[("HOME", "/home/me")]), None);
+        assert_eq!(
+            paths_and_sources(&dirs),
+            [
+                (
+                    "/home/me/.local/share/tabry".into(),
+                    ConfigSource::XdgDataHome
+                ),
+                ("/usr/local/share/tabry".into(), ConfigSource::XdgDataDirs),
+                ("/usr/share/tabry".into(), ConfigSource::XdgDataDirs),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_dirs_precedence() {
+        let root = temp_dir("precedence");
+        let project = root.join("project");
+        fs::create_dir_all(project.join("src/.tabry")).unwrap();
+        fs::create_dir_all(project.join(".tabry")).unwrap();
+        let cwd = project.join("src/deep");
+        fs::create_dir_all(&cwd).unwrap();
+
+        let env = [
+            ("HOME", "/home/me"),
+            ("TABRY_IMPORT_PATH", "~/tabry::/opt/tabry"),
+            ("XDG_DATA_HOME", "/data"),
+            ("XDG_DATA_DIRS", "/sys1:/sys2"),
+            ("TABRY_PROJECT_CONFIGS", "1"),
+        ];
+        let dirs = config_dirs_with(vars(&env), Some(&cwd));
+        let project_dir = |p: &str| project.join(p).to_string_lossy().into_owned();
+        assert_eq!(
+            paths_and_sources(&dirs),
+            [
+                (project_dir("src/.tabry"), ConfigSource::Project),
+                (project_dir(".tabry"), ConfigSource::Project),
+                ("/home/me/tabry".into(), ConfigSource::ImportPath),
+                ("/opt/tabry".into(), ConfigSource::ImportPath),
+                ("/data/tabry".into(), ConfigSource::XdgDataHome),
+                ("/sys1/tabry".into(), ConfigSource::XdgDataDirs),
+                ("/sys2/tabry".into(), ConfigSource::XdgDataDirs),
+            ]
+        );
+
+        // project configs are opt-in
+        let dirs = config_dirs_with(vars(&env[..4]), Some(&cwd));
+        assert_eq!(dirs[0].source, ConfigSource::ImportPath);
+        let mut no_project_env = env[..4].to_vec();
+        no_project_env.push(("TABRY_PROJECT_CONFIGS", "0"));
+        let dirs = config_dirs_with(vars(&no_project_env), Some(&cwd));
+        assert_eq!(dirs[0].source, ConfigSource::ImportPath);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_finds_configs_in_order_of_precedence() {
+        let root = temp_dir("find");
+        let dirs: Vec<ConfigDir> = [
+            ("a", ConfigSource::Project),
+            ("b", ConfigSource::ImportPath),
+            ("missing", ConfigSource::XdgDataHome),
+        ]
+        .into_iter()
+        .map(|(name, source)| ConfigDir {
+            path: root.join(name),
+            source,
+        })
+        .collect();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/foo.json"), "{}").unwrap();
+        fs::write(root.join("b/foo.tabry"), "").unwrap();
+        fs::write(root.join("b/bar.tabry"), "").unwrap();
+
+        assert_eq!(
+            configs_in_dirs(&dirs, "foo"),
+            [
+                FoundConfig {
+                    path: root.join("a/foo.json"),
+                    source: ConfigSource::Project
+                },
+                FoundConfig {
+                    path: root.join("b/foo.tabry"),
+                    source: ConfigSource::ImportPath
+                },
+            ]
+        );
+        assert!(configs_in_dirs(&dirs, "baz").is_empty());
+
+        let mut configs = configs_for_all_commands(&dirs).unwrap();
+        configs.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            configs,
+            [
+                (
+                    "bar".to_owned(),
+                    FoundConfig {
+                        path: root.join("b/bar.tabry"),
+                        source: ConfigSource::ImportPath
+                    }
+                ),
+                (
+                    "foo".to_owned(),
+                    FoundConfig {
+                        path: root.join("a/foo.json"),
+                        source: ConfigSource::Project
+                    }
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+/// `tabry debug mycmd`: a REPL showing how tabry completes command lines for a command. For each
+/// line typed (after the command name, with the cursor at `<TAB>` or the end), it shows what
+/// each token was taken to be, the subs and machine state they lead to, which `opts` were used
+/// to find options (and how long each took), and the options found.
+///
+/// As in `tabry complete`, a command line given to an `opts command` arg is completed with the
+/// command's own config, if it has one. Completion history isn't used or recorded.
+use color_eyre::eyre::Result;
+use std::io::{BufRead, Write};
+use std::time::Instant;
+
+use super::{load_config, resolve_command, shell_tokenizer, spec_test, ResolvedCommand};
+use crate::engine::{machine::Machine, options_finder::OptionsFinder};
+
+/// Print how the tokens (the ones before the cursor, and the one being completed) are completed.
+fn debug_tokens(
+    command: &str,
+    resolved: ResolvedCommand,
+    tokens: &[String],
+    last_token: &str,
+    compline: &str,
+    out: &mut impl Write,
+) -> Result<()> {
+    let tokens = [&resolved.leading_args[..], tokens].concat();
+    let width = tokens
+        .iter()
+        .map(|t| format!("{:?}", t).len())
+        .max()
+        .unwrap_or(0);
+    let mut machine = Machine::new(resolved.config);
+    writeln!(out, "tokens:")?;
+    for (i, token) in tokens.iter().enumerate() {
+        let found = machine.next(token)?;
+        let quoted = format!("{:?}", token);
+        let from_alias = if i < resolved.leading_args.len() {
+            " (from alias)"
+        } else {
+            ""
+        };
+        writeln!(out, "  {:width$}  {}{}", quoted, found, from_alias)?;
+    }
+    let result = machine.to_result();
+
+    let subs: Vec<&str> = result
+        .sub_stack
+        .iter()
+        .map(|sub| sub.name.as_deref().unwrap_or(command))
+        .collect();
+    writeln!(out, "subs: {}", subs.join(" > "))?;
+    writeln!(out, "state: {:?}", result.state)?;
+
+    if let Some((inner, args)) = result.state.command_args().and_then(<[_]>::split_first) {
+        let inner_basename = inner.split('/').next_back().unwrap_or("");
+        if let Some(inner_resolved) = resolve_command(inner_basename)? {
+            writeln!(out, "-- completing {} with its own config:", inner_basename)?;
+            return debug_tokens(
+                inner_basename,
+                inner_resolved,
+                args,
+                last_token,
+                compline,
+                out,
+            );
+        }
+    }
+
+    writeln!(out, "completing {:?}:", last_token)?;
+    let start = Instant::now();
+    let found = OptionsFinder::new(result, true)
+        .with_compline(compline)
+        .options(last_token)?;
+    let elapsed = start.elapsed();
+    for source in &found.sources {
+        writeln!(
+            out,
+            "  {}: added {} in {:?}",
+            source.source, source.added, source.elapsed
+        )?;
+    }
+    writeln!(
+        out,
+        "options ({} in {:?}):",
+        found.options.len() + found.special_options.len(),
+        elapsed
+    )?;
+    for opt in &found.options {
+        match &opt.desc {
+            Some(desc) => writeln!(out, "  {}  -- {}", opt.value, desc)?,
+            None => writeln!(out, "  {}", opt.value)?,
+        }
+    }
+    for special in &found.special_options {
+        writeln!(out, "  special: {}", special.replace('\t', " | "))?;
+    }
+    Ok(())
+}
+
+/// Show how a line typed after the command name is completed.
+fn debug_line(command: &str, line: &str, out: &mut impl Write) -> Result<()> {
+    let (compline, comppoint) = spec_test::split_at_cursor(&format!("{} {}", command, line));
+    let tokenized = shell_tokenizer::split_with_comppoint(&compline, comppoint);
+    let resolved = load_config(command)?;
+    debug_tokens(
+        command,
+        resolved,
+        &tokenized.arguments,
+        &tokenized.last_argument,
+        &compline,
+        out,
+    )
+}
+
+/// Run the REPL for a command, reading lines from stdin until EOF.
+pub fn debug_repl(command: &str) -> Result<()> {
+    // Check there is a config before asking for anything
+    load_config(command)?;
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    eprintln!(
+        "Type what comes after `{}`, with the cursor at <TAB> or the end (^D to quit).",
+        command
+    );
+    loop {
+        write!(stdout, "{} ", command)?;
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            writeln!(stdout)?;
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        // The config is loaded again for each line, so edits to it are picked up
+        if let Err(e) = debug_line(command, line, &mut stdout) {
+            writeln!(stdout, "error: {:#}", e)?;
+        }
+        writeln!(stdout)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_output(tokens: &[&str], last_token: &str) -> String {
+        let config = crate::lang::compile(
+            &std::fs::read_to_string("fixtures/spec_test/mycmd.tabry").unwrap(),
+        )
+        .unwrap();
+        let resolved = ResolvedCommand {
+            config,
+            leading_args: vec!["push".to_owned()],
+        };
+        let tokens: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+        let mut out = vec![];
+        debug_tokens("mycmd", resolved, &tokens, last_token, "", &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_debug_tokens() {
+        let output = debug_output(&["--remote"], "u");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "tokens:",
+                "  \"push\"      subcommand push (from alias)",
+                "  \"--remote\"  flag remote, taking the next token",
+                "subs: mycmd > push",
+            ]
+        );
+        assert_eq!(lines[5], "completing \"u\":");
+        assert!(lines[6].starts_with("  opts const \"origin\": added 0 in "));
+        assert!(lines[7].starts_with("  opts const \"upstream\": added 1 in "));
+        assert!(lines[8].starts_with("options (1 in "));
+        assert_eq!(lines[9..], ["  upstream"]);
+    }
+}
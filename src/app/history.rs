@@ -0,0 +1,351 @@
+/// Completion history, used to rank options by frecency (`set rank frecency`).
+///
+/// Shells don't tell us which option was picked, so it is inferred: after offering options we
+/// remember them (along with the tokens before the one being completed) as "pending", by key. If
+/// the next completion for the same command (within a few minutes) has tokens extending the
+/// pending tokens with one of the options offered, that option is recorded as accepted.
+///
+/// History is only read and written for commands (or subs) using `set rank frecency`. It is
+/// stored in $TABRY_HISTORY_FILE, or $XDG_STATE_HOME/tabry/history.json
+/// (~/.local/state/tabry/history.json by default).
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+/// Only this many options are kept per key; the ones used least recently are dropped.
+const MAX_ENTRIES_PER_KEY: usize = 200;
+
+/// Options offered are forgotten after this long: a completion much later isn't taken to be
+/// continuing the one which offered them.
+const PENDING_MAX_AGE: u64 = 10 * 60;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    /// key (see `history_key`) -> option value -> usage
+    #[serde(default)]
+    entries: HashMap<String, HashMap<String, HistoryEntry>>,
+    /// key (see `history_key`) -> options offered for it, not yet known to be used or not
+    #[serde(default)]
+    pending_completions: HashMap<String, PendingCompletion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntry {
+    count: u32,
+    /// seconds since the epoch
+    last_used: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingCompletion {
+    command: String,
+    tokens: Vec<String>,
+    candidates: Vec<String>,
+    /// seconds since the epoch
+    offered_at: u64,
+}
+
+/// Key under which options are recorded: the command and the subs given, plus the flag if
+/// completing a flag argument (e.g. "vehicles move crash --speed").
+pub fn history_key(command: &str, subs: &[String], current_flag: Option<&str>) -> String {
+    let mut key = command.to_owned();
+    for sub in subs {
+        key.push(' ');
+        key.push_str(sub);
+    }
+    if let Some(flag) = current_flag {
+        key.push_str(" --");
+        key.push_str(flag);
+    }
+    key
+}
+
+pub fn history_file() -> Option<PathBuf> {
+    if let Ok(file) = std::env::var("TABRY_HISTORY_FILE") {
+        return Some(PathBuf::from(file));
+    }
+    let state_dir = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".local/state"),
+    };
+    Some(state_dir.join("tabry/history.json"))
+}
+
+/// Weight of a use depending on how long ago it was.
+fn recency_weight(age: u64) -> f64 {
+    if age < HOUR {
+        4.0
+    } else if age < DAY {
+        2.0
+    } else if age < WEEK {
+        1.0
+    } else {
+        0.5
+    }
+}
+
+impl History {
+    /// Load history from a file. A missing or unreadable file gives an empty history: history
+    /// is only used for ranking, so it's never worth failing a completion over.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save history to a file. It is written to a temporary file (per process, as another shell
+    /// may be completing at the same time) which is then renamed into place, so readers never
+    /// see a partly written file.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".tmp{}", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, serde_json::to_string(self)?)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_path);
+            })
+    }
+
+    /// Record an option as accepted under `key`.
+    pub fn record(&mut self, key: &str, value: &str, now: u64) {
+        let values = self.entries.entry(key.to_owned()).or_default();
+        let entry = values.entry(value.to_owned()).or_insert(HistoryEntry {
+            count: 0,
+            last_used: now,
+        });
+        entry.count += 1;
+        entry.last_used = now;
+
+        if values.len() > MAX_ENTRIES_PER_KEY {
+            if let Some(oldest) = values
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(v, _)| v.clone())
+            {
+                values.remove(&oldest);
+            }
+        }
+    }
+
+    /// Check whether options offered earlier for `command` were completed, given the tokens (not
+    /// including the one being completed) of a new completion for it. Records the accepted
+    /// option, if any, and forgets the options offered for the command, and any offered too long
+    /// ago.
+    pub fn infer_accepted(&mut self, command: &str, tokens: &[String], now: u64) {
+        self.pending_completions
+            .retain(|_, pending| now.saturating_sub(pending.offered_at) <= PENDING_MAX_AGE);
+        let keys: Vec<String> = self
+            .pending_completions
+            .iter()
+            .filter(|(_, pending)| pending.command == command)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            let Some(pending) = self.pending_completions.remove(&key) else {
+                continue;
+            };
+            if !tokens.starts_with(&pending.tokens) {
+                continue;
+            }
+            if let Some(token) = tokens.get(pending.tokens.len()) {
+                if pending.candidates.contains(token) {
+                    self.record(&key, token, now);
+                }
+            }
+        }
+    }
+
+    /// Remember options offered, so they can be recorded if the user goes on to use one.
+    pub fn set_pending(
+        &mut self,
+        command: &str,
+        key: &str,
+        tokens: &[String],
+        candidates: Vec<String>,
+        now: u64,
+    ) {
+        self.pending_completions.insert(
+            key.to_owned(),
+            PendingCompletion {
+                command: command.to_owned(),
+                tokens: tokens.to_vec(),
+                candidates,
+                offered_at: now,
+            },
+        );
+    }
+
+    /// Frecency scores for options recorded under `key`: the number of times each was used,
+    /// weighted by how recently it was last used.
+    pub fn scores(&self, key: &str, now: u64) -> HashMap<String, f64> {
+        self.entries
+            .get(key)
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|(value, e)| {
+                        let age = now.saturating_sub(e.last_used);
+                        (value.clone(), e.count as f64 * recency_weight(age))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_history_key() {
+        assert_eq!(history_key("vehicles", &[], None), "vehicles");
+        assert_eq!(
+            history_key("vehicles", &tokens(&["move", "crash"]), Some("speed")),
+            "vehicles move crash --speed"
+        );
+    }
+
+    #[test]
+    fn test_infers_accepted_option_from_next_completion() {
+        let mut history = History::default();
+        let key = history_key("vehicles", &tokens(&["move"]), None);
+        history.set_pending(
+            "vehicles",
+            &key,
+            &tokens(&["move"]),
+            tokens(&["go", "stop"]),
+            1000,
+        );
+        history.infer_accepted("vehicles", &tokens(&["move", "stop"]), 1000);
+
+        assert_eq!(
+            history.scores(&key, 1000),
+            HashMap::from([("stop".to_owned(), 4.0)])
+        );
+        assert!(history.pending_completions.is_empty());
+    }
+
+    #[test]
+    fn test_doesnt_record_options_that_werent_used() {
+        let mut history = History::default();
+        history.set_pending(
+            "vehicles",
+            "vehicles",
+            &[],
+            tokens(&["build", "move"]),
+            1000,
+        );
+        // different command
+        history.infer_accepted("other", &tokens(&["move"]), 1000);
+        assert!(history.scores("vehicles", 1000).is_empty());
+
+        // same tokens, nothing completed yet
+        history.set_pending(
+            "vehicles",
+            "vehicles",
+            &[],
+            tokens(&["build", "move"]),
+            1000,
+        );
+        history.infer_accepted("vehicles", &[], 1000);
+        assert!(history.scores("vehicles", 1000).is_empty());
+
+        // something not offered
+        history.set_pending(
+            "vehicles",
+            "vehicles",
+            &[],
+            tokens(&["build", "move"]),
+            1000,
+        );
+        history.infer_accepted("vehicles", &tokens(&["mover"]), 1000);
+        assert!(history.scores("vehicles", 1000).is_empty());
+    }
+
+    #[test]
+    fn test_keeps_options_offered_for_other_commands() {
+        let mut history = History::default();
+        history.set_pending(
+            "vehicles",
+            "vehicles",
+            &[],
+            tokens(&["build", "move"]),
+            1000,
+        );
+        history.set_pending("other", "other", &[], tokens(&["build"]), 1000);
+        history.infer_accepted("other", &tokens(&["build"]), 1010);
+        history.infer_accepted("vehicles", &tokens(&["move"]), 1020);
+
+        assert_eq!(
+            history.scores("vehicles", 1020),
+            HashMap::from([("move".to_owned(), 4.0)])
+        );
+        assert_eq!(
+            history.scores("other", 1020),
+            HashMap::from([("build".to_owned(), 4.0)])
+        );
+    }
+
+    #[test]
+    fn test_forgets_options_offered_long_ago() {
+        let mut history = History::default();
+        history.set_pending(
+            "vehicles",
+            "vehicles",
+            &[],
+            tokens(&["build", "move"]),
+            1000,
+        );
+        history.infer_accepted("vehicles", &tokens(&["move"]), 1000 + PENDING_MAX_AGE + 1);
+
+        assert!(history.scores("vehicles", 5000).is_empty());
+        assert!(history.pending_completions.is_empty());
+    }
+
+    #[test]
+    fn test_saves_and_loads_history() {
+        let dir = std::env::temp_dir().join(format!("tabry-history-test-{}", std::process::id()));
+        let path = dir.join("history.json");
+        let mut history = History::default();
+        history.record("vehicles", "build", 1000);
+        history.save(&path).unwrap();
+        // saved over an existing file, without leaving the temporary file behind
+        history.save(&path).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let loaded = History::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            loaded.scores("vehicles", 1000),
+            HashMap::from([("build".to_owned(), 4.0)])
+        );
+    }
+
+    #[test]
+    fn test_scores_weight_count_by_recency() {
+        let mut history = History::default();
+        history.record("vehicles", "build", 0);
+        history.record("vehicles", "build", 0);
+        history.record("vehicles", "build", 0);
+        history.record("vehicles", "move", 2 * WEEK);
+
+        let scores = history.scores("vehicles", 2 * WEEK + 10);
+        assert_eq!(scores["build"], 1.5);
+        assert_eq!(scores["move"], 4.0);
+    }
+}
@@ -0,0 +1,49 @@
+/// Logging (with `tracing`), to stderr or to the file in $TABRY_LOG_FILE, so logs never get mixed
+/// up with the completions tabry prints.
+///
+/// The level is set with $TABRY_LOG: `error`, `warn`, `info`, `debug` or `trace`, or any
+/// `tracing_subscriber` filter, e.g. `tabry::engine=trace`. TABRY_DEBUG=1 means `debug`, and
+/// otherwise only errors are logged. At `debug`, each token the machine reads and each `opts`
+/// used to find options (with how long it took) is logged; at `trace`, also the machine state
+/// after each token.
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use crate::core::util;
+
+pub fn init_logging() {
+    let default_level = if util::env_flag("TABRY_DEBUG") {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::ERROR
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(default_level.into())
+        .with_env_var("TABRY_LOG")
+        .from_env_lossy();
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let Some(path) = std::env::var_os("TABRY_LOG_FILE") else {
+        builder
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .init();
+        return;
+    };
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => builder
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+            .init(),
+        Err(e) => {
+            builder
+                .with_writer(std::io::stderr)
+                .with_ansi(std::io::stderr().is_terminal())
+                .init();
+            tracing::warn!("can't open log file {}: {}", path.to_string_lossy(), e);
+        }
+    }
+}
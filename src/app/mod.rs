@@ -1,40 +1,312 @@
 // see lib.rs for hierarchy description
+mod aliases;
 mod cached_jsons;
+mod clap_spec;
 mod config_finder;
+mod debug;
+mod history;
+mod logging;
 mod shell_tokenizer;
+mod spec_test;
 
 /// Main app functionality
 use color_eyre::eyre::{eyre, Context, Result};
 use std::io::Read;
+use std::sync::OnceLock;
+
+pub use debug::debug_repl;
+pub use logging::init_logging;
+pub use spec_test::run_tests;
 
 use crate::{
-    core::{config, util},
-    engine::{machine, options_finder},
+    core::{config, types},
+    engine::{machine, machine_state::MachineStateMode, options_finder},
     lang,
 };
 
+/// Load a config file, compiling it if necessary.
+fn load_config_file(config_file: &str) -> Result<config::TabryConf> {
+    let compiled_config_file = cached_jsons::resolve_and_compile_cache_file(config_file)?;
+    let config = config::TabryConf::from_file(&compiled_config_file)
+        .with_context(|| "invalid config file")?;
+    Ok(config)
+}
+
+/// The config for a command, and the tokens to put before the ones typed (for an alias like
+/// `kp = kubectl --context prod`).
+struct ResolvedCommand {
+    config: config::TabryConf,
+    leading_args: Vec<String>,
+}
+
+/// tabry's own CLI, to complete `tabry` when there is no config file for it
+static SELF_COMMAND: OnceLock<clap::Command> = OnceLock::new();
+
+/// Set tabry's own CLI definition, from which a config is generated to complete `tabry` itself.
+pub fn set_self_command(command: clap::Command) {
+    let _ = SELF_COMMAND.set(command);
+}
+
+/// Load the config for a command from the files found for it (see `config_finder`), merging it
+/// with the configs it extends.
+fn load_command_config(command: &str) -> Result<Option<config::TabryConf>> {
+    let found: Vec<String> = config_finder::find_all_tabry_configs(command)
+        .into_iter()
+        .map(|found| found.path.to_string_lossy().into_owned())
+        .collect();
+    if found.is_empty() {
+        return Ok(SELF_COMMAND
+            .get()
+            .filter(|self_command| self_command.get_name() == command)
+            .map(|self_command| clap_spec::config_from_clap(self_command.clone())));
+    }
+    let compiled_config_file = cached_jsons::resolve_and_compile_merged_cache_file(&found)?;
+    let config = config::TabryConf::from_file(&compiled_config_file)
+        .with_context(|| "invalid config file")?;
+    Ok(Some(config))
+}
+
+/// Commands with configs, with the aliases their configs declare (`cmd name,alias`), from the
+/// index in the cache (see `cached_jsons::cmd_aliases`). A config which extends the next one
+/// found for the command gets its aliases too, as when they are merged.
+fn all_configs_with_aliases() -> Result<Vec<(String, Vec<String>)>> {
+    let configs = config_finder::all_configs()?;
+    let paths: Vec<String> = configs
+        .iter()
+        .map(|(_, found)| found.path.to_string_lossy().into_owned())
+        .collect();
+    let mut res = vec![];
+    for ((cmd, _), first) in configs.into_iter().zip(cached_jsons::cmd_aliases(&paths)) {
+        let mut aliases = first.aliases;
+        if first.extends {
+            let extended: Vec<String> = config_finder::find_all_tabry_configs(&cmd)
+                .into_iter()
+                .skip(1)
+                .map(|found| found.path.to_string_lossy().into_owned())
+                .collect();
+            for next in cached_jsons::cmd_aliases(&extended) {
+                aliases.extend(next.aliases);
+                if !next.extends {
+                    break;
+                }
+            }
+        }
+        res.push((cmd, aliases));
+    }
+    Ok(res)
+}
+
+/// Find the config for a command by its file name, or by a `cmd name,alias` statement.
+fn find_config_by_name(command: &str) -> Result<Option<config::TabryConf>> {
+    if let Some(config) = load_command_config(command)? {
+        return Ok(Some(config));
+    }
+    // Load it as the command it's an alias of, so it is merged with any configs it extends
+    let Some((name, _)) = all_configs_with_aliases()?
+        .into_iter()
+        .find(|(_, aliases)| aliases.iter().any(|alias| alias == command))
+    else {
+        return Ok(None);
+    };
+    load_command_config(&name)
+}
+
+/// Find the config for a command (see `config_finder`), which may be an alias: from the user's
+/// aliases file (see `aliases`), or declared with `cmd name,alias`. A file for the command itself
+/// takes precedence over both.
+fn resolve_command(command_basename: &str) -> Result<Option<ResolvedCommand>> {
+    if let Some(config) = load_command_config(command_basename)? {
+        return Ok(Some(ResolvedCommand {
+            config,
+            leading_args: vec![],
+        }));
+    }
+    if let Some(alias) = aliases::user_aliases()
+        .into_iter()
+        .find(|alias| alias.name == command_basename)
+    {
+        let Some(config) = find_config_by_name(&alias.command)? else {
+            return Ok(None);
+        };
+        return Ok(Some(ResolvedCommand {
+            config,
+            leading_args: alias.leading_args,
+        }));
+    }
+    Ok(
+        find_config_by_name(command_basename)?.map(|config| ResolvedCommand {
+            config,
+            leading_args: vec![],
+        }),
+    )
+}
+
+/// Like `resolve_command`, but it is an error if there is no config.
+fn load_config(command_basename: &str) -> Result<ResolvedCommand> {
+    match resolve_command(command_basename)? {
+        Some(resolved) => Ok(resolved),
+        // The error lists the dirs searched
+        None => Err(config_finder::config_not_found(command_basename).into()),
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// What kind of completion the shell is asking for (bash's COMP_TYPE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompType {
+    /// The first TAB (COMP_TYPE 9): just the values, from which the shell inserts the unambiguous
+    /// prefix
+    Normal,
+    /// Listing the options, e.g. on a second TAB (COMP_TYPE 63 `?`, 33 `!` or 64 `@`): values with
+    /// their descriptions, in columns
+    List,
+    /// Menu completion (COMP_TYPE 37 `%`), which cycles through the values
+    Menu,
+}
+
+impl std::str::FromStr for CompType {
+    type Err = std::convert::Infallible;
+
+    /// Anything unrecognized (e.g. COMP_TYPE 42 from bash's insert-completions) is treated as a
+    /// normal completion, so new or unusual completion types still get values.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "list" | "63" | "33" | "64" => CompType::List,
+            "menu" | "37" => CompType::Menu,
+            _ => CompType::Normal,
+        })
+    }
+}
+
+/// How `tabry complete` prints options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompletionOutput {
+    /// Print descriptions after a tab (for fish)
+    pub include_descriptions: bool,
+    /// Escape options to continue the token being completed (with any quote opened in it), for
+    /// shells which insert them as-is
+    pub escape: bool,
+    pub comp_type: Option<CompType>,
+}
+
 fn print_options(
-    config_filename: &str,
+    command_basename: &str,
+    compline: &str,
+    config: config::TabryConf,
     tokens: &[String],
     last_token: &str,
-    include_descriptions: bool,
+    output: &CompletionOutput,
+    quote_style: shell_tokenizer::QuoteStyle,
 ) -> Result<()> {
-    let config =
-        config::TabryConf::from_file(config_filename).with_context(|| "invalid config file")?;
     let result =
         machine::Machine::run(config, tokens).with_context(|| "Tabry machine parse error")?;
 
-    if util::is_debug() {
-        println!("{}", serde_json::to_string_pretty(&result.state)?);
+    tracing::debug!("machine state: {:?}", result.state);
+
+    // Completing another command line (`opts command`, e.g. `sudo CMD...`): start again with the
+    // command's own config, if it has one. If not, the options finder delegates to the shell.
+    if let Some((command, args)) = result.state.command_args().and_then(<[_]>::split_first) {
+        let command_basename = command.split('/').next_back().unwrap_or("");
+        if let Some(resolved) = resolve_command(command_basename)? {
+            let tokens = [&resolved.leading_args[..], args].concat();
+            return print_options(
+                command_basename,
+                compline,
+                resolved.config,
+                &tokens,
+                last_token,
+                output,
+                quote_style,
+            );
+        }
     }
 
-    let options_finder = options_finder::OptionsFinder::new(result, include_descriptions);
-    let opts = options_finder.options(last_token)?;
+    // Completion history is only used (and recorded) with `set rank frecency`
+    let settings = types::TabrySettings::resolve(&result.sub_stack);
+    let history_file = if settings.rank == Some(types::TabryRank::Frecency) {
+        history::history_file()
+    } else {
+        None
+    };
+    let history_key = history::history_key(
+        command_basename,
+        &result.state.subcommand_stack,
+        match &result.state.mode {
+            MachineStateMode::Flagarg { current_flag } => Some(current_flag.as_str()),
+            MachineStateMode::Subcommand | MachineStateMode::Command { .. } => None,
+        },
+    );
+    let mut history = history_file.as_deref().map(history::History::load);
+
+    let include_descriptions =
+        output.include_descriptions || output.comp_type == Some(CompType::List);
+    let mut options_finder =
+        options_finder::OptionsFinder::new(result, include_descriptions).with_compline(compline);
+    if let Some(history) = &mut history {
+        history.infer_accepted(command_basename, tokens, now());
+        options_finder = options_finder.with_frecency_scores(history.scores(&history_key, now()));
+    }
+    let mut opts = options_finder.options(last_token)?;
 
-    for opt in &opts.options {
+    if let (Some(history), Some(history_file)) = (&mut history, &history_file) {
+        let candidates = opts.options.iter().map(|o| o.value.clone()).collect();
+        history.set_pending(command_basename, &history_key, tokens, candidates, now());
+        // Failing to save history shouldn't break completion
+        if let Err(e) = history.save(history_file) {
+            tracing::warn!(
+                "failed to save history to {}: {}",
+                history_file.display(),
+                e
+            );
+        }
+    }
+
+    // Directories and `--flag=`s are continued rather than followed by another arg
+    if !opts.options.is_empty()
+        && opts
+            .options
+            .iter()
+            .all(|opt| opt.value.ends_with('/') || opt.value.ends_with('='))
+    {
+        opts.special_options.push("nospace".to_owned());
+    }
+
+    if matches!(output.comp_type, Some(CompType::Normal | CompType::Menu)) {
+        // Nothing is listed, so there is no point in describing what is expected
+        opts.special_options
+            .retain(|special| !special.starts_with("description_if_optionless\t"));
+    }
+
+    let values: Vec<String> = opts
+        .options
+        .iter()
+        .map(|opt| {
+            if output.escape {
+                quote_style.escape(&opt.value)
+            } else {
+                opt.value.clone()
+            }
+        })
+        .collect();
+    // Shells without descriptions (bash) are given them in the values when listing. The values
+    // still differ where they did, so the prefix the shell inserts can't include a description.
+    let columns = output.comp_type == Some(CompType::List)
+        && values.len() > 1
+        && opts.options.iter().any(|opt| opt.desc.is_some());
+    let width = values.iter().map(|v| v.chars().count()).max().unwrap_or(0);
+    for (opt, value) in opts.options.iter().zip(&values) {
         match opt.desc.as_ref() {
-            Some(desc) => println!("{}	{}", opt.value, desc),
-            None => println!("{}", opt.value),
+            Some(desc) if columns => println!("{:width$}  -- {}", value, desc),
+            Some(desc) if output.include_descriptions && output.comp_type.is_none() => {
+                println!("{}	{}", value, desc)
+            }
+            _ => println!("{}", value),
         }
     }
 
@@ -52,48 +324,182 @@ fn print_options(
 }
 
 // This runs using the filename plus 2nd arg as compline (shellsplits ARGV[2])
-pub fn run_as_compline(compline: &str, comppoint: &str, include_descriptions: bool) -> Result<()> {
+pub fn run_as_compline(compline: &str, comppoint: &str, output: &CompletionOutput) -> Result<()> {
     let comppoint = comppoint
         .parse::<usize>()
         .wrap_err_with(|| eyre!("Invalid compoint: {}", comppoint))?;
 
-    let tokenized_result = shell_tokenizer::split_with_comppoint(compline, comppoint)
-        .wrap_err_with(|| {
-            eyre!(
-                "Failed to split compline {} on comppoint {}",
-                compline,
-                comppoint
-            )
-        })?;
+    let tokenized_result = shell_tokenizer::split_with_comppoint(compline, comppoint);
+    complete_tokenized(tokenized_result, compline, output)
+}
+
+/// Like `run_as_compline`, but given words already split by the shell (see
+/// `shell_tokenizer::split_words`). If `words` is None, they are read from stdin, NUL-separated.
+pub fn run_with_words(
+    words: Option<Vec<String>>,
+    cword: usize,
+    cur: Option<&str>,
+    quoted: bool,
+    output: &CompletionOutput,
+) -> Result<()> {
+    let words = match words {
+        Some(words) => words,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            let input = input.strip_suffix('\0').unwrap_or(&input);
+            if input.is_empty() {
+                vec![]
+            } else {
+                input.split('\0').map(str::to_owned).collect()
+            }
+        }
+    };
+
+    // The command line up to the cursor, for TABRY_COMPLINE
+    let mut compline_words: Vec<String> = words
+        .iter()
+        .take(cword)
+        .map(|word| {
+            if quoted {
+                word.clone()
+            } else {
+                shell_words::quote(word).into_owned()
+            }
+        })
+        .collect();
+    compline_words.extend(cur.map(str::to_owned).or_else(|| words.get(cword).cloned()));
+    let compline = compline_words.join(" ");
+
+    let tokenized_result = shell_tokenizer::split_words(&words, cword, cur, quoted);
+    complete_tokenized(tokenized_result, &compline, output)
+}
 
+fn complete_tokenized(
+    tokenized_result: shell_tokenizer::TokenizedResult,
+    compline: &str,
+    output: &CompletionOutput,
+) -> Result<()> {
     let args = tokenized_result.arguments;
     let last_arg = tokenized_result.last_argument;
+    let quote_style = tokenized_result.quote_style;
 
-    let config_file = config_finder::find_tabry_config(&tokenized_result.command_basename)?;
-    let compiled_config_file = cached_jsons::resolve_and_compile_cache_file(&config_file)?;
+    if tokenized_result.command_basename.is_empty() {
+        // Completing the command itself. The shell scripts don't ask for this (the shells
+        // complete command names themselves), but other callers of `tabry complete` can.
+        for command in command_names()? {
+            if command.starts_with(&last_arg) {
+                if output.escape {
+                    println!("{}", quote_style.escape(&command));
+                } else {
+                    println!("{}", command);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let resolved = load_config(&tokenized_result.command_basename)?;
+    let tokens = [resolved.leading_args, args].concat();
 
     print_options(
-        &compiled_config_file,
-        &args[..],
+        &tokenized_result.command_basename,
+        compline,
+        resolved.config,
+        &tokens,
         &last_arg,
-        include_descriptions,
+        output,
+        quote_style,
     )?;
     Ok(())
 }
 
-pub fn compile() -> Result<()> {
+/// Check a complete command line (command name followed by arguments) against the command's
+/// flag constraints (reqd flags, `exclusive` and `requires`), printing any violations. Returns
+/// whether the command line is valid.
+pub fn validate(command_line: &[String]) -> Result<bool> {
+    let Some((command, args)) = command_line.split_first() else {
+        return Err(eyre!("No command given"));
+    };
+    let command_basename = command.split('/').next_back().unwrap_or("");
+    let resolved = load_config(command_basename)?;
+    let tokens = [&resolved.leading_args[..], args].concat();
+    let result = machine::Machine::run(resolved.config, &tokens)
+        .with_context(|| "Tabry machine parse error")?;
+
+    let violations = result.flag_violations();
+    for violation in &violations {
+        println!("{}", violation);
+    }
+    Ok(violations.is_empty())
+}
+
+pub fn compile(link: bool) -> Result<()> {
     let mut input = String::new();
     std::io::stdin().read_to_string(&mut input).unwrap();
-    let tabry_conf = lang::compile(&input)?;
+    let mut tabry_conf = lang::compile(&input)?;
+    if link {
+        tabry_conf = tabry_conf.link()?;
+    }
     let json = serde_json::to_string_pretty(&tabry_conf)?;
     print!("{}", json);
     Ok(())
 }
 
-pub fn commands() {
-    for command in config_finder::all_supported_commands().unwrap() {
+/// Print which config file is used for a command, and any it shadows. Returns whether one was
+/// found.
+pub fn which(command: &str) -> bool {
+    let found = config_finder::find_all_tabry_configs(command);
+    // whether the files so far extend the next one (rather than shadowing it)
+    let mut extending = true;
+    for (i, config) in found.iter().enumerate() {
+        let prefix = match (i, extending) {
+            (0, _) => "",
+            (_, true) => "  extends ",
+            (_, false) => "  shadows ",
+        };
+        println!("{}{} ({})", prefix, config.path.display(), config.source);
+        extending = extending
+            && config
+                .path
+                .to_str()
+                .and_then(|path| load_config_file(path).ok())
+                .is_some_and(|config| config.extends);
+    }
+    if found.is_empty() {
+        eprintln!("no config found for {}; searched:", command);
+        for dir in config_finder::config_dirs() {
+            eprintln!("  {} ({})", dir.path.display(), dir.source);
+        }
+    }
+    !found.is_empty()
+}
+
+/// The commands with configs, including aliases (from `cmd name,alias` statements and the user's
+/// aliases file) and tabry itself.
+fn command_names() -> Result<Vec<String>> {
+    let mut commands = vec![];
+    for (command, aliases) in all_configs_with_aliases()? {
+        // A broken config still gets completion set up (to show its errors), just not aliases
+        commands.push(command);
+        commands.extend(aliases);
+    }
+    commands.extend(aliases::user_aliases().into_iter().map(|alias| alias.name));
+    if let Some(self_command) = SELF_COMMAND.get() {
+        commands.push(self_command.get_name().to_owned());
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    commands.retain(|command| seen.insert(command.clone()));
+    Ok(commands)
+}
+
+/// Print the commands to complete, for the shell scripts to set up completion for.
+pub fn commands() -> Result<()> {
+    for command in command_names()? {
         println!("{}", command);
     }
+    Ok(())
 }
 
 fn escape(s: &str) -> String {
@@ -152,3 +558,17 @@ pub fn fish(imports_path: Option<&str>, no_auto: bool, uniq_fn_id: Option<&str>)
         println!("tabry_completion_init_all{}", fn_id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comp_type_treats_unknown_kinds_as_normal() {
+        assert_eq!("63".parse(), Ok(CompType::List));
+        assert_eq!("menu".parse(), Ok(CompType::Menu));
+        assert_eq!("9".parse(), Ok(CompType::Normal));
+        // bash's insert-completions (M-*)
+        assert_eq!("42".parse(), Ok(CompType::Normal));
+    }
+}
@@ -1,56 +1,305 @@
-const COMPPOINT_SENTINEL: &str = "\u{FFFF}";
+// Splits the command line being completed into words, as the shell would, up to the cursor.
+// Only the simple command the cursor is in is returned: everything before a command separator
+// (`;`, `&&`, `||`, `|`, `&`, `(`, a newline) or inside an unclosed `$(`/backtick is another
+// command. Environment assignments before the command (`FOO=1 cmd`) are skipped.
+//
+// The line is cut at the cursor, so the rest of the current token isn't included, and an
+// unterminated quote there is fine: it's how the token being completed is quoted, which is
+// reported so completions can be quoted the same way.
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// Not in quotes (but special characters may be escaped with backslashes)
+    #[default]
+    None,
+    /// In `'...'`
+    Single,
+    /// In `"..."`
+    Double,
+    /// In `$'...'`, where backslash escapes like `\n` are interpreted
+    Dollar,
+}
+
+impl QuoteStyle {
+    /// Escape `value` so that typed in this quoting (after the opening quote, if any, which the
+    /// shell keeps when it replaces the token), it is read back as `value`.
+    pub fn escape(self, value: &str) -> String {
+        let mut res = String::with_capacity(value.len());
+        for c in value.chars() {
+            match self {
+                QuoteStyle::None => match c {
+                    '\n' => res.push_str("$'\\n'"),
+                    ' ' | '\t' | '\'' | '"' | '\\' | '$' | '`' | '&' | '|' | ';' | '<' | '>'
+                    | '(' | ')' | '*' | '?' | '[' | ']' | '{' | '}' | '!' | '#' | '~' => {
+                        res.push('\\');
+                        res.push(c);
+                    }
+                    _ => res.push(c),
+                },
+                QuoteStyle::Double => {
+                    if matches!(c, '"' | '\\' | '$' | '`') {
+                        res.push('\\');
+                    }
+                    res.push(c);
+                }
+                QuoteStyle::Single => match c {
+                    '\'' => res.push_str("'\\''"),
+                    _ => res.push(c),
+                },
+                QuoteStyle::Dollar => match c {
+                    '\\' | '\'' => {
+                        res.push('\\');
+                        res.push(c);
+                    }
+                    '\n' => res.push_str("\\n"),
+                    '\t' => res.push_str("\\t"),
+                    '\r' => res.push_str("\\r"),
+                    c if c.is_ascii_control() => res.push_str(&format!("\\x{:02x}", c as u32)),
+                    _ => res.push(c),
+                },
+            }
+        }
+        res
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct TokenizedResult {
     pub command_basename: String,
     pub arguments: Vec<String>,
     pub last_argument: String,
+    /// Quoting open at the cursor, in the last argument
+    pub quote_style: QuoteStyle,
 }
 
-/// Reproduction of the algorithm used in Ruby tabry. Replace comppoint with a sentinel value,
-/// split as a shell would, then find the token with the sentinel. This is slightly better than
-/// just chopping off the string after the comppoint because iv the lat arg is correctly quoted but
-/// we are in the middle, shell_words split will return an error. TODO: However this still isn't
-/// perfect as other shell completion seems to _not_take into account the rest of the last token as
-/// we here. This could probably still be improved (maybe we should chop off the rest of the
-/// last_token after the sentinel). We should also investigate use of COMP_WORDS and COMP_CWORD
-/// which may help with special quoting situations not handled by shell_words.
-pub fn split_with_comppoint(
-    compline: &str,
-    comppoint: usize,
-) -> Result<TokenizedResult, shell_words::ParseError> {
-    let mut compline = compline.to_owned();
-
-    // compline is characters, not bytes. Find where to insert the sentinel.
-    let comppoint_byte: usize = compline
-        .chars()
-        .take(comppoint)
-        .map(|c| c.len_utf16())
-        .sum();
-    compline.insert_str(comppoint_byte, COMPPOINT_SENTINEL);
-
-    let all_tokens: Vec<String> = shell_words::split(&compline)?;
-
-    let last_arg_index: usize = all_tokens
-        .iter()
-        .enumerate()
-        .find(|(_i, s)| s.contains(COMPPOINT_SENTINEL))
-        .map(|(i, _s)| i)
-        .unwrap();
-
-    let (command, arguments, last_argument) = match &all_tokens[0..=last_arg_index] {
-        [last_arg] => ("", vec![], last_arg.as_str()),
-        [cmd, args @ .., last_arg] => (cmd.as_str(), Vec::from(args), last_arg.as_str()),
-        [] => unreachable!(), // we'll always have at least the sentinel
-    };
+#[derive(Default)]
+struct Word {
+    text: String,
+    /// Whether the word has been started (it may be empty, e.g. `""`)
+    started: bool,
+    /// Whether everything so far is an unquoted variable name, so an `=` makes it an assignment
+    could_be_assignment: bool,
+    is_assignment: bool,
+}
+
+impl Word {
+    fn push_unquoted(&mut self, c: char) {
+        if !self.started {
+            self.started = true;
+            self.could_be_assignment = c.is_ascii_alphabetic() || c == '_';
+        } else if c == '=' && self.could_be_assignment {
+            self.is_assignment = true;
+            self.could_be_assignment = false;
+        } else if !(c.is_ascii_alphanumeric() || c == '_') {
+            self.could_be_assignment = false;
+        }
+        self.text.push(c);
+    }
+
+    fn push_quoted(&mut self, c: char) {
+        self.started = true;
+        self.could_be_assignment = false;
+        self.text.push(c);
+    }
+
+    fn start_quote(&mut self) {
+        self.started = true;
+        self.could_be_assignment = false;
+    }
+}
+
+/// The words of a simple command (and the one in progress)
+#[derive(Default)]
+struct Command {
+    words: Vec<Word>,
+    current: Word,
+    quote: QuoteStyle,
+}
 
-    let command_basename = command.split('/').last().unwrap_or("").to_owned();
-    let last_argument = last_argument.replace(COMPPOINT_SENTINEL, "");
+impl Command {
+    fn end_word(&mut self) {
+        if self.current.started {
+            self.words.push(std::mem::take(&mut self.current));
+        }
+    }
+}
 
-    Ok(TokenizedResult {
+/// Interpret the escape sequence `\c` in `$'...'`
+fn dollar_escape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'a' => '\x07',
+        'b' => '\x08',
+        'e' | 'E' => '\x1b',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        _ => c,
+    }
+}
+
+fn tokenize(line: &str) -> Command {
+    // Commands containing the cursor: the last is the innermost (e.g. inside a `$(`)
+    let mut stack: Vec<Command> = vec![Command::default()];
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let nested = stack.len() > 1;
+        let cmd = stack.last_mut().unwrap();
+        match cmd.quote {
+            QuoteStyle::Single => match c {
+                '\'' => cmd.quote = QuoteStyle::None,
+                _ => cmd.current.push_quoted(c),
+            },
+            QuoteStyle::Dollar => match c {
+                '\'' => cmd.quote = QuoteStyle::None,
+                '\\' => {
+                    if let Some(escaped) = chars.next() {
+                        cmd.current.push_quoted(dollar_escape(escaped));
+                    }
+                }
+                _ => cmd.current.push_quoted(c),
+            },
+            QuoteStyle::Double => match c {
+                '"' => cmd.quote = QuoteStyle::None,
+                '\\' => match chars.next() {
+                    Some(escaped @ ('$' | '`' | '"' | '\\')) => cmd.current.push_quoted(escaped),
+                    Some('\n') => {}
+                    Some(other) => {
+                        cmd.current.push_quoted('\\');
+                        cmd.current.push_quoted(other);
+                    }
+                    None => {}
+                },
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    stack.push(Command::default());
+                }
+                '`' => stack.push(Command::default()),
+                _ => cmd.current.push_quoted(c),
+            },
+            QuoteStyle::None => match c {
+                ' ' | '\t' => cmd.end_word(),
+                '\\' => match chars.next() {
+                    Some('\n') => {}
+                    Some(escaped) => cmd.current.push_quoted(escaped),
+                    None => {}
+                },
+                '\'' => {
+                    cmd.current.start_quote();
+                    cmd.quote = QuoteStyle::Single;
+                }
+                '"' => {
+                    cmd.current.start_quote();
+                    cmd.quote = QuoteStyle::Double;
+                }
+                '$' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    cmd.current.start_quote();
+                    cmd.quote = QuoteStyle::Dollar;
+                }
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    stack.push(Command::default());
+                }
+                '`' if nested => {
+                    // end of a backtick substitution, which is part of the enclosing word
+                    stack.pop();
+                    stack.last_mut().unwrap().current.start_quote();
+                }
+                '`' => stack.push(Command::default()),
+                ')' if nested => {
+                    stack.pop();
+                    stack.last_mut().unwrap().current.start_quote();
+                }
+                ';' | '&' | '|' | '(' | ')' | '\n' => *cmd = Command::default(),
+                _ => cmd.current.push_unquoted(c),
+            },
+        }
+    }
+
+    stack.pop().unwrap()
+}
+
+/// A word given literally (already unquoted)
+fn literal_word(text: &str) -> Word {
+    let mut word = Word::default();
+    text.chars().for_each(|c| word.push_unquoted(c));
+    word.started = true;
+    word.text = text.to_owned();
+    word
+}
+
+fn result_from_words(words: Vec<Word>, current: Word, quote: QuoteStyle) -> TokenizedResult {
+    let mut words = words.into_iter().skip_while(|word| word.is_assignment);
+    let (command_basename, arguments) = match words.next() {
+        Some(command) => {
+            let basename = command.text.split('/').next_back().unwrap_or("").to_owned();
+            (basename, words.map(|word| word.text).collect())
+        }
+        None => (String::new(), vec![]),
+    };
+
+    TokenizedResult {
         command_basename,
         arguments,
-        last_argument,
-    })
+        last_argument: current.text,
+        quote_style: quote,
+    }
+}
+
+/// Tokenize `compline` up to `comppoint` (in characters).
+pub fn split_with_comppoint(compline: &str, comppoint: usize) -> TokenizedResult {
+    let line: String = compline.chars().take(comppoint).collect();
+    let Command {
+        words,
+        current,
+        quote,
+    } = tokenize(&line);
+    result_from_words(words, current, quote)
+}
+
+/// Use words already split by the shell (e.g. bash's COMP_WORDS, or fish's `commandline -opc`),
+/// where `cword` is the index of the word being completed. `cur` is the part of it before the
+/// cursor, as typed (with any quotes); by default, the whole word. If `quoted`, the words are as
+/// typed too, and are unquoted here; otherwise they are taken literally.
+pub fn split_words(
+    words: &[String],
+    cword: usize,
+    cur: Option<&str>,
+    quoted: bool,
+) -> TokenizedResult {
+    let unquote = |word: &str| {
+        let Command { words, current, .. } = tokenize(word);
+        let mut words = words;
+        words.extend(Some(current).filter(|c| c.started));
+        words
+    };
+    let mut before = vec![];
+    for word in words.iter().take(cword) {
+        if quoted {
+            // a word could have unquoted spaces, e.g. in words split by the user rather than
+            // the shell
+            before.extend(unquote(word));
+        } else {
+            before.push(literal_word(word));
+        }
+    }
+
+    let (current, quote) = match (cur, words.get(cword)) {
+        (Some(cur), _) => {
+            let Command { current, quote, .. } = tokenize(cur);
+            (current, quote)
+        }
+        (None, Some(word)) if quoted => {
+            let Command { current, quote, .. } = tokenize(word);
+            (current, quote)
+        }
+        (None, Some(word)) => (literal_word(word), QuoteStyle::None),
+        (None, None) => (Word::default(), QuoteStyle::None),
+    };
+    result_from_words(before, current, quote)
 }
 
 #[cfg(test)]
@@ -64,7 +313,7 @@ mod tests {
         expected_arguments: Vec<&str>,
         expected_last_argument: &str,
     ) {
-        let res = split_with_comppoint(compline, comppoint).unwrap();
+        let res = split_with_comppoint(compline, comppoint);
         let expected_args_vec = expected_arguments
             .iter()
             .map(|s| String::from(*s))
@@ -74,6 +323,10 @@ mod tests {
         assert_eq!(res.last_argument, expected_last_argument);
     }
 
+    fn quote_style_at_end(compline: &str) -> QuoteStyle {
+        split_with_comppoint(compline, compline.chars().count()).quote_style
+    }
+
     #[test]
     fn test_tokenizer_returns_command_basename_argument_last_argument() {
         assert_result(
@@ -81,13 +334,13 @@ mod tests {
             13, // input
             "bar",
             vec!["abc"],
-            "def", // expected
+            "d", // expected
         );
     }
 
     #[test]
     fn test_tokenizer_treats_only_one_argument_as_last_arg() {
-        assert_result("foo bar", 5, "foo", vec![], "bar");
+        assert_result("foo bar", 5, "foo", vec![], "b");
     }
 
     #[test]
@@ -110,11 +363,143 @@ mod tests {
 
     #[test]
     fn test_tokenizer_treats_only_one_token_as_last_arg() {
-        assert_result("abc", 2, "", vec![], "abc");
+        assert_result("abc", 2, "", vec![], "ab");
     }
 
     #[test]
     fn test_tokenizer_supports_empty_strings() {
         assert_result("", 0, "", vec![], "");
     }
+
+    #[test]
+    fn test_tokenizer_ignores_rest_of_token_after_cursor() {
+        assert_result("git checkout mainline", 17, "git", vec!["checkout"], "main");
+        assert_result("git 'check out' x", 10, "git", vec![], "check");
+    }
+
+    #[test]
+    fn test_tokenizer_handles_unterminated_quotes() {
+        assert_result(r#"vi "my fi"#, 9, "vi", vec![], "my fi");
+        assert_eq!(quote_style_at_end(r#"vi "my fi"#), QuoteStyle::Double);
+        assert_eq!(quote_style_at_end("vi 'my fi"), QuoteStyle::Single);
+        assert_eq!(quote_style_at_end("vi $'my\\tfi"), QuoteStyle::Dollar);
+        assert_eq!(quote_style_at_end("vi 'my fi'le"), QuoteStyle::None);
+        assert_eq!(quote_style_at_end("vi my\\ fi"), QuoteStyle::None);
+    }
+
+    #[test]
+    fn test_tokenizer_handles_escapes() {
+        assert_result(r"vi my\ file\ ", 13, "vi", vec![], "my file ");
+        assert_result(
+            r#"echo "a\"b\c" $'d\te\''"#,
+            23,
+            "echo",
+            vec![r#"a"b\c"#],
+            "d\te'",
+        );
+        assert_result("echo a\\\nb", 9, "echo", vec![], "ab");
+    }
+
+    #[test]
+    fn test_tokenizer_completes_only_the_current_command() {
+        assert_result("cd foo && git ch", 16, "git", vec![], "ch");
+        assert_result("make; ls -l | grep fo", 21, "grep", vec![], "fo");
+        assert_result("(cd x || tabry wh", 17, "tabry", vec![], "wh");
+        assert_result("echo $(git branch --l", 21, "git", vec!["branch"], "--l");
+        assert_result("echo \"$(kubectl get po", 22, "kubectl", vec!["get"], "po");
+        assert_result("echo `date +", 12, "date", vec![], "+");
+        assert_result("echo $(date) fo", 15, "echo", vec![""], "fo");
+    }
+
+    #[test]
+    fn test_tokenizer_skips_environment_assignments() {
+        assert_result("FOO=1 BAR='a b' kubectl g", 25, "kubectl", vec![], "g");
+        assert_result("kubectl FOO=1 ", 14, "kubectl", vec!["FOO=1"], "");
+        assert_result("'FOO=1' kubectl ", 16, "FOO=1", vec!["kubectl"], "");
+        assert_result("FOO=1 ku", 8, "", vec![], "ku");
+    }
+
+    fn split(words: &[&str], cword: usize, cur: Option<&str>, quoted: bool) -> TokenizedResult {
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        split_words(&words, cword, cur, quoted)
+    }
+
+    #[test]
+    fn test_split_words() {
+        let res = split(
+            &["/usr/bin/git", "checkout", "mainline"],
+            2,
+            Some("main"),
+            false,
+        );
+        assert_eq!(res.command_basename, "git");
+        assert_eq!(res.arguments, ["checkout"]);
+        assert_eq!(res.last_argument, "main");
+
+        // the current word defaults to the one at cword, and can be past the last word
+        assert_eq!(split(&["git", "che"], 1, None, false).last_argument, "che");
+        let res = split(&["git", "checkout"], 2, None, false);
+        assert_eq!((res.arguments.len(), res.last_argument.as_str()), (1, ""));
+
+        // literal words aren't unquoted, e.g. from fish
+        let res = split(&["vi", "it's here", "a=b"], 3, Some("'my fi"), false);
+        assert_eq!(res.arguments, ["it's here", "a=b"]);
+        assert_eq!(res.last_argument, "my fi");
+        assert_eq!(res.quote_style, QuoteStyle::Single);
+
+        // quoted words are, e.g. from bash
+        let res = split(&["FOO=1", "vi", r"it\'s\ here", "\"my fi"], 3, None, true);
+        assert_eq!(res.command_basename, "vi");
+        assert_eq!(res.arguments, ["it's here"]);
+        assert_eq!(res.last_argument, "my fi");
+        assert_eq!(res.quote_style, QuoteStyle::Double);
+
+        // words split on COMP_WORDBREAKS (":" and "=") stay as the shell split them
+        let res = split(&["cmd", "--opt", "=", "a", ":", "b"], 5, None, true);
+        assert_eq!(res.arguments, ["--opt", "=", "a", ":"]);
+        assert_eq!(res.last_argument, "b");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            QuoteStyle::None.escape("my file (1).txt"),
+            r"my\ file\ \(1\).txt"
+        );
+        assert_eq!(
+            QuoteStyle::Double.escape(r#"say "$hi" \o/"#),
+            r#"say \"\$hi\" \\o/"#
+        );
+        assert_eq!(QuoteStyle::Single.escape("it's"), r"it'\''s");
+        assert_eq!(QuoteStyle::Dollar.escape("a'b\tc"), r"a\'b\tc");
+
+        // typed after the opening quote (and closed), each is read back as the original value
+        let values = [
+            "plain",
+            "my file",
+            "it's",
+            r#"a "b" $c `d` \e"#,
+            "tab\tnew\nline",
+            "*?!#~",
+        ];
+        let styles = [
+            (QuoteStyle::None, "", ""),
+            (QuoteStyle::Double, "\"", "\""),
+            (QuoteStyle::Single, "'", "'"),
+            (QuoteStyle::Dollar, "$'", "'"),
+        ];
+        for value in values {
+            for (style, open, close) in styles {
+                let line = format!("cmd {}{}{} ", open, style.escape(value), close);
+                let res = split_with_comppoint(&line, line.chars().count());
+                assert_eq!(res.arguments, [value], "{:?} escaped as {}", style, line);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_counts_characters_not_bytes() {
+        assert_result("echo 🦀 ü", 8, "echo", vec!["🦀"], "ü");
+        assert_result("echo 日本語", 7, "echo", vec![], "日本");
+    }
 }
@@ -0,0 +1,406 @@
+/// `tabry test`: check a tabry config against a file of test cases, as the crate's own tests do
+/// for the engine (fixtures/vehicles-expectations.json). The cases file is TOML:
+///
+/// ```toml
+/// # Output for `opts shell` commands, by command, and for `opts builtin`, as "builtin:<name>"
+/// # (a string of lines, or a list)
+/// [stubs]
+/// "git branch --format='%(refname:short)'" = ["main", "feature"]
+/// "builtin:users" = ["alice\tAlice Liddell", "bob"]
+///
+/// [[cases]]
+/// name = "completes branches" # optional, defaults to the line
+/// line = "mygit checkout m<TAB> --force"
+/// options = ["main"]
+/// specials = []
+/// state = { subs = ["checkout"], flags = {} }
+/// ```
+///
+/// The line is tokenized as by `tabry complete`, with the cursor at `<TAB>` (or the end), and
+/// its first word is taken to be the command. `options` and `specials` are the options and the
+/// special options (file, dir, ...) for the token at the cursor, in order. `state` is compared
+/// with the given keys of the machine state for the tokens before it (as serialized for
+/// `TABRY_AUTOCOMPLETE_STATE`, e.g. `subs`, `args`, `flags`, `flag_args` and `mode`). Only the
+/// expectations given are checked, but a case needs at least one.
+///
+/// `opts shell` commands are never run and `opts builtin` never reads the system (users, hosts,
+/// git refs, ...), so tests are hermetic: their output comes from the stubs (for builtins, a line
+/// per option, with an optional description after a tab), and a case needing one without a stub
+/// fails.
+///
+/// With `--update`, failing expectations are replaced with the actual results (and a case
+/// without any gets `options`, and `specials` if there are any), keeping the rest of the file.
+use color_eyre::eyre::{eyre, Context, Result};
+use std::collections::HashMap;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, Value};
+
+use super::shell_tokenizer;
+use crate::core::config::TabryConf;
+use crate::engine::{machine::Machine, options_finder::OptionsFinder};
+
+const CURSOR: &str = "<TAB>";
+
+/// A command line without the cursor marker, and the position of the cursor (in characters): at
+/// the marker, or the end.
+pub(super) fn split_at_cursor(line: &str) -> (String, usize) {
+    match line.find(CURSOR) {
+        Some(i) => (line.replacen(CURSOR, "", 1), line[..i].chars().count()),
+        None => (line.to_owned(), line.chars().count()),
+    }
+}
+
+fn load_spec(spec_file: &str) -> Result<TabryConf> {
+    let config = if spec_file.ends_with(".json") {
+        TabryConf::from_file(spec_file)?
+    } else {
+        crate::lang::compile(&std::fs::read_to_string(spec_file)?)?
+    };
+    // checks for missing includes and include loops up front, not in some case that hits them
+    Ok(config.link()?)
+}
+
+fn strings(item: &Item) -> Option<Vec<String>> {
+    let array = item.as_array()?;
+    array
+        .iter()
+        .map(|value| value.as_str().map(str::to_owned))
+        .collect()
+}
+
+fn load_stubs(doc: &DocumentMut) -> Result<HashMap<String, String>> {
+    let mut stubs = HashMap::new();
+    let Some(table) = doc.get("stubs") else {
+        return Ok(stubs);
+    };
+    let table = table
+        .as_table_like()
+        .ok_or_else(|| eyre!("stubs must be a table"))?;
+    for (command, output) in table.iter() {
+        let output = match (output.as_str(), strings(output)) {
+            (Some(output), _) => output.to_owned(),
+            (None, Some(lines)) => lines.join("\n"),
+            _ => {
+                return Err(eyre!(
+                    "stub for {command} must be a string or a list of strings"
+                ))
+            }
+        };
+        stubs.insert(command.to_owned(), output);
+    }
+    Ok(stubs)
+}
+
+/// What tabry finds for a test case's command line
+struct Actual {
+    options: Vec<String>,
+    specials: Vec<String>,
+    state: serde_json::Value,
+}
+
+fn run_case(config: &TabryConf, stubs: &HashMap<String, String>, line: &str) -> Result<Actual> {
+    let (compline, comppoint) = split_at_cursor(line);
+    let tokenized = shell_tokenizer::split_with_comppoint(&compline, comppoint);
+    let result = Machine::run(config.clone(), &tokenized.arguments)?;
+    let state = serde_json::to_value(&result.state)?;
+    let found = OptionsFinder::new(result, false)
+        .with_compline(&compline)
+        .with_stubs(stubs.clone())
+        .options(&tokenized.last_argument)?;
+    Ok(Actual {
+        options: found.options.into_iter().map(|opt| opt.value).collect(),
+        specials: found.special_options,
+        state,
+    })
+}
+
+fn toml_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::String(s) => s.value().clone().into(),
+        Value::Integer(i) => (*i.value()).into(),
+        Value::Float(f) => (*f.value()).into(),
+        Value::Boolean(b) => (*b.value()).into(),
+        Value::Datetime(d) => d.value().to_string().into(),
+        Value::Array(array) => array.iter().map(toml_to_json).collect(),
+        Value::InlineTable(table) => table
+            .iter()
+            .map(|(k, v)| (k.to_owned(), toml_to_json(v)))
+            .collect(),
+    }
+}
+
+/// TOML has no null, which isn't in machine states anyway; it becomes an empty string.
+fn json_to_toml(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => "".into(),
+        serde_json::Value::Bool(b) => (*b).into(),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or_default().into(),
+        },
+        serde_json::Value::String(s) => s.as_str().into(),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.iter().map(json_to_toml).collect::<Array>())
+        }
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let table: InlineTable = keys
+                .into_iter()
+                .map(|k| (k.as_str(), json_to_toml(&map[k])))
+                .collect();
+            Value::InlineTable(table)
+        }
+    }
+}
+
+fn string_array(values: &[String]) -> Item {
+    toml_edit::value(values.iter().collect::<Array>())
+}
+
+/// Lines showing how `actual` differs from `expected`: values in both with "  ", values only
+/// expected with "- " and values only found with "+ ", in order.
+fn diff_lines(expected: &[String], actual: &[String]) -> Vec<String> {
+    // lcs[i][j] is the length of the longest common subsequence of expected[i..] and actual[j..]
+    let mut lcs = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Check a case's expectations, returning a description of each failure. With `update`, the
+/// failing expectations are replaced with the actual results.
+fn check_case(case: &mut Table, actual: &Actual, update: bool) -> Result<Vec<String>> {
+    let mut failures = vec![];
+    let mut checked_any = false;
+    for (key, actual_values) in [("options", &actual.options), ("specials", &actual.specials)] {
+        let Some(item) = case.get(key) else {
+            continue;
+        };
+        checked_any = true;
+        let expected = strings(item).ok_or_else(|| eyre!("{key} must be a list of strings"))?;
+        if &expected != actual_values {
+            let diff = diff_lines(&expected, actual_values);
+            failures.push(format!("{key}:\n    {}", diff.join("\n    ")));
+            if update {
+                case[key] = string_array(actual_values);
+            }
+        }
+    }
+
+    if let Some(item) = case.get_mut("state") {
+        checked_any = true;
+        let table = item
+            .as_table_like_mut()
+            .ok_or_else(|| eyre!("state must be a table"))?;
+        for (key, expected) in table.iter_mut() {
+            let expected_json = match expected.as_value() {
+                Some(value) => toml_to_json(value),
+                None => return Err(eyre!("state.{key} must be a value")),
+            };
+            let actual_json = actual
+                .state
+                .get(key.get())
+                .ok_or_else(|| eyre!("no such key in the machine state: {key}"))?;
+            if &expected_json != actual_json {
+                failures.push(format!(
+                    "state.{key}: expected {expected_json}, got {actual_json}"
+                ));
+                if update {
+                    *expected = toml_edit::value(json_to_toml(actual_json));
+                }
+            }
+        }
+    }
+
+    if !checked_any {
+        failures.push("no expectations (options, specials or state) given".to_owned());
+        if update {
+            case["options"] = string_array(&actual.options);
+            if !actual.specials.is_empty() {
+                case["specials"] = string_array(&actual.specials);
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Run the test cases in `cases_file` against the config in `spec_file` (a .tabry or .json
+/// file), printing the results. With `update`, failing expectations are rewritten in
+/// `cases_file`. Returns whether all cases passed (or were updated).
+pub fn run_tests(spec_file: &str, cases_file: &str, update: bool) -> Result<bool> {
+    let config = load_spec(spec_file).with_context(|| format!("loading {spec_file}"))?;
+    let mut doc: DocumentMut = std::fs::read_to_string(cases_file)?
+        .parse()
+        .with_context(|| format!("parsing {cases_file}"))?;
+    let stubs = load_stubs(&doc)?;
+    let cases = doc
+        .get_mut("cases")
+        .and_then(Item::as_array_of_tables_mut)
+        .ok_or_else(|| eyre!("{cases_file} has no [[cases]]"))?;
+
+    let (mut passed, mut failed, mut errored) = (0, 0, 0);
+    for case in cases.iter_mut() {
+        let line = case
+            .get("line")
+            .and_then(Item::as_str)
+            .ok_or_else(|| eyre!("every case needs a line"))?
+            .to_owned();
+        let name = case.get("name").and_then(Item::as_str).unwrap_or(&line);
+        let name = name.to_owned();
+
+        let actual = match run_case(&config, &stubs, &line) {
+            Ok(actual) => actual,
+            Err(err) => {
+                println!("ERROR {name}: {err}");
+                errored += 1;
+                continue;
+            }
+        };
+        let failures = check_case(case, &actual, update)?;
+        if failures.is_empty() {
+            passed += 1;
+            continue;
+        }
+        failed += 1;
+        println!("{} {name}", if update { "UPDATED" } else { "FAIL" });
+        if name != line {
+            println!("  line: {line}");
+        }
+        for failure in failures {
+            println!("  {failure}");
+        }
+    }
+
+    if update && failed > 0 {
+        std::fs::write(cases_file, doc.to_string())?;
+    }
+    let failed_word = if update { "updated" } else { "failed" };
+    println!("{passed} passed, {failed} {failed_word}, {errored} errors");
+    Ok(errored == 0 && (update || failed == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = "fixtures/spec_test/mycmd.tabry";
+
+    fn load_case(cases: &str) -> (TabryConf, HashMap<String, String>, Table) {
+        let doc: DocumentMut = cases.parse().unwrap();
+        let stubs = load_stubs(&doc).unwrap();
+        let case = doc["cases"].as_array_of_tables().unwrap().get(0).unwrap();
+        (load_spec(SPEC).unwrap(), stubs, case.clone())
+    }
+
+    fn check(cases: &str, update: bool) -> (Vec<String>, Table) {
+        let (config, stubs, mut case) = load_case(cases);
+        let line = case["line"].as_str().unwrap().to_owned();
+        let actual = run_case(&config, &stubs, &line).unwrap();
+        let failures = check_case(&mut case, &actual, update).unwrap();
+        (failures, case)
+    }
+
+    #[test]
+    fn test_example_cases_pass() {
+        assert!(run_tests(SPEC, "fixtures/spec_test/cases.toml", false).unwrap());
+    }
+
+    #[test]
+    fn test_reports_differences() {
+        let (failures, _) = check(
+            r#"
+            [stubs]
+            "echo main; echo feature" = "main\nfeature"
+            [[cases]]
+            line = "mycmd checkout <TAB>"
+            options = ["main", "develop"]
+            state = { subs = ["push"] }
+            "#,
+            false,
+        );
+        assert_eq!(
+            failures,
+            [
+                "options:\n      main\n    - develop\n    + feature",
+                r#"state.subs: expected ["push"], got ["checkout"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_stub() {
+        let (config, stubs, _) = load_case("[[cases]]\nline = \"mycmd checkout \"");
+        let err = run_case(&config, &stubs, "mycmd checkout <TAB>")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "No stub output for \"echo main; echo feature\""
+        );
+
+        let (config, _, _) = load_case("[[cases]]\nline = \"mycmd push --reviewer \"");
+        let err = run_case(&config, &HashMap::new(), "mycmd push --reviewer <TAB>")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "No stub output for \"builtin:users\"");
+    }
+
+    #[test]
+    fn test_update() {
+        let (failures, case) = check(
+            r#"
+            [[cases]]
+            line = "mycmd <TAB>"
+            "#,
+            true,
+        );
+        assert_eq!(failures.len(), 1);
+        assert_eq!(strings(&case["options"]).unwrap(), ["checkout", "push"]);
+        assert!(case.get("specials").is_none());
+
+        let (failures, case) = check(
+            r#"
+            [[cases]]
+            line = "mycmd push --remote <TAB>"
+            options = []
+            state = { mode = "subcommand" }
+            "#,
+            true,
+        );
+        assert_eq!(failures.len(), 2);
+        assert_eq!(strings(&case["options"]).unwrap(), ["origin", "upstream"]);
+        assert_eq!(case["state"]["mode"].as_str(), Some("flagarg"));
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let to_vec = |s: &str| s.split_whitespace().map(str::to_owned).collect::<Vec<_>>();
+        assert_eq!(
+            diff_lines(&to_vec("a b c d"), &to_vec("a c e d")),
+            ["  a", "- b", "  c", "+ e", "  d"]
+        );
+        assert_eq!(diff_lines(&[], &to_vec("a")), ["+ a"]);
+    }
+}
@@ -10,6 +10,13 @@ use thiserror::Error;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TabryConf {
     pub cmd: Option<String>,
+    /// Other names the command is run as (`cmd kubectl,k`), which use this config too
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd_aliases: Vec<String>,
+    /// This config adds to the next config found for the command rather than replacing it
+    /// (`extends`), see `TabryConf::merge`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub extends: bool,
     pub main: TabryConcreteSub,
     #[serde(default)]
     pub arg_includes: HashMap<String, TabryArgInclude>,
@@ -29,6 +36,8 @@ pub enum TabryConfError {
     InvalidConfig(String),
     #[error("Missing include: {0}")]
     MissingInclude(String),
+    #[error("Include loop: {0}")]
+    IncludeLoop(String),
 }
 
 impl TabryConf {
@@ -89,6 +98,62 @@ impl TabryConf {
         Ok(None)
     }
 
+    /// Like find_in_subs (checking aliases), but ignoring case.
+    pub fn find_in_subs_ignoring_case<'a>(
+        &'a self,
+        subs: &'a [TabrySub],
+        name: &str,
+    ) -> Result<Option<&'a TabryConcreteSub>, TabryConfError> {
+        let name = name.to_lowercase();
+        for sub in self.flatten_subs(subs)? {
+            let sub_name = Self::unwrap_sub_name(sub)?;
+            if sub_name.to_lowercase() == name
+                || sub.aliases.iter().any(|alias| alias.to_lowercase() == name)
+            {
+                return Ok(Some(sub));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the only sub whose name or an alias starts with `prefix`. Returns None if there are
+    /// no such subs or more than one.
+    pub fn find_in_subs_by_unique_prefix<'a>(
+        &'a self,
+        subs: &'a [TabrySub],
+        prefix: &str,
+        ignore_case: bool,
+    ) -> Result<Option<&'a TabryConcreteSub>, TabryConfError> {
+        if prefix.is_empty() {
+            return Ok(None);
+        }
+        let normalize = |s: &str| {
+            if ignore_case {
+                s.to_lowercase()
+            } else {
+                s.to_owned()
+            }
+        };
+        let prefix = normalize(prefix);
+
+        let mut found = None;
+        for sub in self.flatten_subs(subs)? {
+            let sub_name = Self::unwrap_sub_name(sub)?;
+            let matches = normalize(sub_name).starts_with(&prefix)
+                || sub
+                    .aliases
+                    .iter()
+                    .any(|alias| normalize(alias).starts_with(&prefix));
+            if matches {
+                if found.is_some() {
+                    return Ok(None);
+                }
+                found = Some(sub);
+            }
+        }
+        Ok(found)
+    }
+
     pub fn unwrap_sub_name(sub: &TabryConcreteSub) -> Result<&str, TabryConfError> {
         match &sub.name {
             Some(s) => Ok(s.as_ref()),
@@ -170,4 +235,334 @@ impl TabryConf {
         });
         Box::new(iter)
     }
+
+    /// Produce a "linked" copy of this config: every include (of subs, args, flags, and options)
+    /// is expanded in place, so the result has no `arg_includes` or `option_includes` and the
+    /// engine never has to follow an include at completion time. Unlike the lazy lookups above,
+    /// this checks every include up front, returning an error for missing includes and include
+    /// loops (which would otherwise only be found if a completion happens to hit them).
+    pub fn link(&self) -> Result<TabryConf, TabryConfError> {
+        let mut linker = Linker {
+            config: self,
+            stack: vec![],
+        };
+        Ok(TabryConf {
+            cmd: self.cmd.clone(),
+            cmd_aliases: self.cmd_aliases.clone(),
+            extends: self.extends,
+            main: linker.link_sub(&self.main)?,
+            arg_includes: HashMap::new(),
+            option_includes: HashMap::new(),
+        })
+    }
+
+    /// Add the subs, args, flags, etc. of `overlay` to this config. Things with the same name in
+    /// both are merged rather than repeated:
+    /// * subs with the same name are merged recursively
+    /// * flags and args with the same name get the overlay's options after their own, and the
+    ///   overlay's description/title if it has one (flags are also `required`, `repeatable`, etc.
+    ///   if the overlay's are)
+    /// * `set` settings given in the overlay take precedence
+    /// * includes with the same name are replaced by the overlay's (everywhere they are used)
+    ///
+    /// Anything else (unnamed args, include args/flags/subs, exclusive flags and requirements) is
+    /// added after the config's own.
+    pub fn merge(mut self, overlay: TabryConf) -> TabryConf {
+        if self.cmd.is_none() {
+            self.cmd = overlay.cmd;
+        }
+        union_into(&mut self.cmd_aliases, overlay.cmd_aliases);
+        self.extends = false;
+        merge_sub(&mut self.main, overlay.main);
+        self.arg_includes.extend(overlay.arg_includes);
+        self.option_includes.extend(overlay.option_includes);
+        self
+    }
+}
+
+fn union_into(items: &mut Vec<String>, more: Vec<String>) {
+    for item in more {
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+}
+
+fn merge_sub(sub: &mut TabryConcreteSub, overlay: TabryConcreteSub) {
+    sub.description = overlay.description.or(sub.description.take());
+    union_into(&mut sub.aliases, overlay.aliases);
+
+    for overlay_sub in overlay.subs {
+        let existing = match &overlay_sub {
+            TabrySub::TabryConcreteSub(o) if o.name.is_some() => {
+                sub.subs.iter_mut().find_map(|s| match s {
+                    TabrySub::TabryConcreteSub(s) if s.name == o.name => Some(s),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        match (existing, overlay_sub) {
+            (Some(s), TabrySub::TabryConcreteSub(o)) => merge_sub(s, o),
+            (_, overlay_sub) => sub.subs.push(overlay_sub),
+        }
+    }
+
+    for overlay_arg in overlay.args {
+        let existing = match &overlay_arg {
+            TabryArg::TabryConcreteArg(o) if o.name.is_some() => {
+                sub.args.iter_mut().find_map(|a| match a {
+                    TabryArg::TabryConcreteArg(a) if a.name == o.name => Some(a),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+        match (existing, overlay_arg) {
+            (Some(a), TabryArg::TabryConcreteArg(o)) => {
+                a.title = o.title.or(a.title.take());
+                a.description = o.description.or(a.description.take());
+                a.options.extend(o.options);
+            }
+            (_, overlay_arg) => sub.args.push(overlay_arg),
+        }
+    }
+
+    for overlay_flag in overlay.flags {
+        let existing = match &overlay_flag {
+            TabryFlag::TabryConcreteFlag(o) => sub.flags.iter_mut().find_map(|f| match f {
+                TabryFlag::TabryConcreteFlag(f) if f.name == o.name => Some(f),
+                _ => None,
+            }),
+            _ => None,
+        };
+        match (existing, overlay_flag) {
+            (Some(f), TabryFlag::TabryConcreteFlag(o)) => {
+                union_into(&mut f.aliases, o.aliases);
+                f.description = o.description.or(f.description.take());
+                f.options.extend(o.options);
+                f.arg |= o.arg;
+                f.required |= o.required;
+                f.repeatable |= o.repeatable;
+                f.negatable |= o.negatable;
+            }
+            (_, overlay_flag) => sub.flags.push(overlay_flag),
+        }
+    }
+
+    sub.exclusive_flags.extend(overlay.exclusive_flags);
+    sub.flag_requirements.extend(overlay.flag_requirements);
+
+    sub.settings = TabrySettings::merge(&sub.settings, &overlay.settings);
+}
+
+/// Helper for TabryConf::link(). Keeps track of the includes currently being expanded so we can
+/// detect loops.
+struct Linker<'a> {
+    config: &'a TabryConf,
+    stack: Vec<String>,
+}
+
+impl<'a> Linker<'a> {
+    fn enter(&mut self, include_desc: String) -> Result<(), TabryConfError> {
+        if self.stack.contains(&include_desc) {
+            let mut chain = self.stack.clone();
+            chain.push(include_desc);
+            return Err(TabryConfError::IncludeLoop(chain.join(" -> ")));
+        }
+        self.stack.push(include_desc);
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    fn link_sub(&mut self, sub: &TabryConcreteSub) -> Result<TabryConcreteSub, TabryConfError> {
+        let mut subs = vec![];
+        let mut args = vec![];
+        let mut flags = vec![];
+        self.link_subs_into(&sub.subs, &mut subs)?;
+        self.link_args_into(&sub.args, &mut args)?;
+        self.link_flags_into(&sub.flags, &mut flags)?;
+        Ok(TabryConcreteSub {
+            subs,
+            args,
+            flags,
+            ..sub.clone()
+        })
+    }
+
+    fn arg_include(&mut self, name: &str) -> Result<&'a TabryArgInclude, TabryConfError> {
+        let include = self.config.get_arg_include(name)?;
+        self.enter(format!("@{}", name))?;
+        Ok(include)
+    }
+
+    fn link_subs_into(
+        &mut self,
+        subs: &[TabrySub],
+        result: &mut Vec<TabrySub>,
+    ) -> Result<(), TabryConfError> {
+        for sub in subs {
+            match sub {
+                TabrySub::TabryIncludeSub { include } => {
+                    let include = self.arg_include(include)?;
+                    self.link_subs_into(&include.subs, result)?;
+                    self.leave();
+                }
+                TabrySub::TabryConcreteSub(s) => {
+                    result.push(TabrySub::TabryConcreteSub(self.link_sub(s)?));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn link_args_into(
+        &mut self,
+        args: &[TabryArg],
+        result: &mut Vec<TabryArg>,
+    ) -> Result<(), TabryConfError> {
+        for arg in args {
+            match arg {
+                TabryArg::TabryIncludeArg { include } => {
+                    let include = self.arg_include(include)?;
+                    self.link_args_into(&include.args, result)?;
+                    self.leave();
+                }
+                TabryArg::TabryConcreteArg(a) => {
+                    let mut options = vec![];
+                    self.link_options_into(&a.options, &mut options)?;
+                    result.push(TabryArg::TabryConcreteArg(TabryConcreteArg {
+                        options,
+                        ..a.clone()
+                    }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn link_flags_into(
+        &mut self,
+        flags: &[TabryFlag],
+        result: &mut Vec<TabryFlag>,
+    ) -> Result<(), TabryConfError> {
+        for flag in flags {
+            match flag {
+                TabryFlag::TabryIncludeFlag { include } => {
+                    let include = self.arg_include(include)?;
+                    self.link_flags_into(&include.flags, result)?;
+                    self.leave();
+                }
+                TabryFlag::TabryConcreteFlag(f) => {
+                    let mut options = vec![];
+                    self.link_options_into(&f.options, &mut options)?;
+                    result.push(TabryFlag::TabryConcreteFlag(TabryConcreteFlag {
+                        options,
+                        ..f.clone()
+                    }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn link_options_into(
+        &mut self,
+        options: &[TabryOpt],
+        result: &mut Vec<TabryOpt>,
+    ) -> Result<(), TabryConfError> {
+        for opt in options {
+            match opt {
+                TabryOpt::Include { value } => {
+                    let include = self.config.get_option_include(value)?;
+                    // option includes live in a different namespace from arg includes
+                    self.enter(format!("opts @{}", value))?;
+                    self.link_options_into(include, result)?;
+                    self.leave();
+                }
+                _ => result.push(opt.clone()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{load_fixture_file, load_fixture_file_text};
+    use assert_json_diff::assert_json_eq;
+
+    fn has_includes(sub: &TabryConcreteSub) -> bool {
+        let opts_have_includes = |opts: &Vec<TabryOpt>| {
+            opts.iter()
+                .any(|opt| matches!(opt, TabryOpt::Include { .. }))
+        };
+        sub.args.iter().any(|arg| match arg {
+            TabryArg::TabryIncludeArg { .. } => true,
+            TabryArg::TabryConcreteArg(a) => opts_have_includes(&a.options),
+        }) || sub.flags.iter().any(|flag| match flag {
+            TabryFlag::TabryIncludeFlag { .. } => true,
+            TabryFlag::TabryConcreteFlag(f) => opts_have_includes(&f.options),
+        }) || sub.subs.iter().any(|s| match s {
+            TabrySub::TabryIncludeSub { .. } => true,
+            TabrySub::TabryConcreteSub(s) => has_includes(s),
+        })
+    }
+
+    #[test]
+    fn test_link_expands_all_includes() {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        let linked = tabry_conf.link().unwrap();
+        assert!(linked.arg_includes.is_empty());
+        assert!(linked.option_includes.is_empty());
+        assert!(!has_includes(&linked.main));
+
+        let go = linked
+            .dig_sub(&vec!["move".to_owned(), "go".to_owned()])
+            .unwrap();
+        let go_arg = linked.expand_args(&go.args).next().unwrap();
+        assert_eq!(go_arg.name.as_deref(), Some("vehicle-type"));
+        let values = go_arg
+            .options
+            .iter()
+            .map(|opt| match opt {
+                TabryOpt::Const { value, .. } => value.as_str(),
+                _ => panic!("unexpected option {:?}", opt),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec!["car", "bike"]);
+    }
+
+    #[test]
+    fn test_link_missing_include() {
+        let tabry_conf: TabryConf = load_fixture_file("missing_include.json");
+        let result = tabry_conf.link();
+        assert!(matches!(result, Err(TabryConfError::MissingInclude(name)) if name == "bar"));
+    }
+
+    #[test]
+    fn test_link_include_loop() {
+        let tabry_conf: TabryConf = load_fixture_file("include_loop.json");
+        let result = tabry_conf.link();
+        assert!(
+            matches!(&result, Err(TabryConfError::IncludeLoop(chain)) if chain == "@a -> @b -> @a"),
+            "unexpected result: {:?}",
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let compile = |file| crate::lang::compile(&load_fixture_file_text(file)).unwrap();
+        let base = compile("merge/base.tabry");
+        let overlay = compile("merge/overlay.tabry");
+        assert!(overlay.extends);
+        let merged = base.merge(overlay);
+        let expected: TabryConf = load_fixture_file("merge/merged.json");
+        assert_json_eq!(merged, expected);
+    }
 }
@@ -8,13 +8,93 @@ pub enum TabryOpt {
     #[serde(rename = "dir")]
     Dir,
     #[serde(rename = "const")]
-    Const { value: String },
+    Const {
+        value: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        case_insensitive: bool,
+    },
     #[serde(rename = "delegate")]
     Delegate { value: String },
+    /// The arg is another full command line (e.g. `sudo CMD...`), completed with the command's
+    /// own config (or delegated to the shell if it has none)
+    #[serde(rename = "command")]
+    Command,
     #[serde(rename = "shell")]
-    Shell { value: String },
+    Shell {
+        value: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        case_insensitive: bool,
+        /// The command only outputs options matching the token being completed (given to it
+        /// in TABRY_PREFIX), so they aren't filtered again
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        prefix_aware: bool,
+    },
     #[serde(rename = "include")]
     Include { value: String },
+    #[serde(rename = "builtin")]
+    Builtin { value: TabryBuiltin },
+}
+
+/// Sources of options built into tabry (`opts builtin git-branches`), see
+/// `engine::builtin_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TabryBuiltin {
+    /// Names of environment variables
+    EnvVars,
+    /// Usernames from /etc/passwd
+    Users,
+    /// Group names from /etc/group
+    Groups,
+    /// Hostnames from ~/.ssh/config and /etc/hosts
+    Hosts,
+    /// IDs of running processes (with the process name as the description)
+    Pids,
+    /// Names of running processes
+    Processes,
+    /// Local git branches of the repository containing the current directory
+    GitBranches,
+    /// Git tags
+    GitTags,
+    /// Git remotes
+    GitRemotes,
+    /// Signal names, as used by `kill -s`
+    Signals,
+}
+
+impl TabryBuiltin {
+    pub const ALL: [TabryBuiltin; 10] = [
+        TabryBuiltin::EnvVars,
+        TabryBuiltin::Users,
+        TabryBuiltin::Groups,
+        TabryBuiltin::Hosts,
+        TabryBuiltin::Pids,
+        TabryBuiltin::Processes,
+        TabryBuiltin::GitBranches,
+        TabryBuiltin::GitTags,
+        TabryBuiltin::GitRemotes,
+        TabryBuiltin::Signals,
+    ];
+
+    /// The name used in tabry files and JSON, e.g. "git-branches"
+    pub fn name(self) -> &'static str {
+        match self {
+            TabryBuiltin::EnvVars => "env-vars",
+            TabryBuiltin::Users => "users",
+            TabryBuiltin::Groups => "groups",
+            TabryBuiltin::Hosts => "hosts",
+            TabryBuiltin::Pids => "pids",
+            TabryBuiltin::Processes => "processes",
+            TabryBuiltin::GitBranches => "git-branches",
+            TabryBuiltin::GitTags => "git-tags",
+            TabryBuiltin::GitRemotes => "git-remotes",
+            TabryBuiltin::Signals => "signals",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +107,8 @@ pub enum TabryArg {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TabryConcreteArg {
     pub name: Option<String>,
+    /// Short description of what is expected, e.g. "file to load"
+    pub title: Option<String>,
     pub description: Option<String>,
     #[serde(default)]
     pub options: Vec<TabryOpt>,
@@ -36,6 +118,15 @@ pub struct TabryConcreteArg {
     pub varargs: bool,
 }
 
+impl TabryConcreteArg {
+    /// Whether the arg is another command line (`opts command`)
+    pub fn takes_command(&self) -> bool {
+        self.options
+            .iter()
+            .any(|opt| matches!(opt, TabryOpt::Command))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TabryConcreteFlag {
     pub name: String,
@@ -49,6 +140,13 @@ pub struct TabryConcreteFlag {
     pub arg: bool,
     #[serde(default)]
     pub required: bool,
+    /// Flag can be given multiple times (e.g. "-v -v" or "--tag a --tag b"). Such flags keep
+    /// being offered after they have been used.
+    #[serde(default)]
+    pub repeatable: bool,
+    /// Flag can also be given as "--no-<name>" to turn it off.
+    #[serde(default)]
+    pub negatable: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,6 +168,109 @@ pub struct TabryConcreteSub {
     pub flags: Vec<TabryFlag>,
     #[serde(default)]
     pub subs: Vec<TabrySub>,
+    #[serde(default)]
+    pub exclusive_flags: Vec<TabryExclusiveFlags>,
+    #[serde(default)]
+    pub flag_requirements: Vec<TabryFlagRequirement>,
+    #[serde(default, skip_serializing_if = "TabrySettings::is_empty")]
+    pub settings: TabrySettings,
+}
+
+/// Settings changing how tokens are matched and options are found. Given with `set` statements,
+/// either at the top level (for the whole command) or in a sub, where they apply to that sub and
+/// its subs. Unset (None) settings are inherited from the parent sub.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TabrySettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_matching: Option<TabrySubMatching>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<TabryFilter>,
+    /// Match subcommands, long flags, and options regardless of case (`set case-insensitive`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_insensitive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<TabryRank>,
+    /// Offer at most this many options (`set max-results 50`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<usize>,
+}
+
+impl TabrySettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The settings in `overlay`, with any it doesn't set taken from `base`.
+    pub fn merge(base: &Self, overlay: &Self) -> Self {
+        Self {
+            sub_matching: overlay.sub_matching.or(base.sub_matching),
+            filter: overlay.filter.or(base.filter),
+            case_insensitive: overlay.case_insensitive.or(base.case_insensitive),
+            rank: overlay.rank.or(base.rank),
+            max_results: overlay.max_results.or(base.max_results),
+        }
+    }
+
+    /// Settings in effect for the last sub in `subs` (given from the main sub down), with
+    /// settings on more specific subs taking precedence.
+    pub fn resolve<'a>(subs: impl IntoIterator<Item = &'a TabryConcreteSub>) -> Self {
+        subs.into_iter().fold(Self::default(), |result, sub| {
+            Self::merge(&result, &sub.settings)
+        })
+    }
+}
+
+/// How a token is matched against subcommand names and aliases.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TabrySubMatching {
+    /// Only exact names and aliases (`set sub-matching exact`)
+    #[default]
+    Exact,
+    /// Also unique prefixes, e.g. "ip a" for "ip addr" (`set sub-matching prefix`)
+    Prefix,
+}
+
+/// How options are filtered by the token being completed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TabryFilter {
+    /// Options starting with the token (`set filter prefix`)
+    #[default]
+    Prefix,
+    /// Options containing the characters of the token in order (`set filter fuzzy`)
+    Fuzzy,
+}
+
+/// The order in which options are offered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TabryRank {
+    /// Subs, then args, then flags, each in the order they appear in the config. Options from
+    /// `opts shell` are kept in the order the command printed them. (`set rank config`)
+    #[default]
+    Config,
+    /// Sorted by value (`set rank alphabetical`)
+    Alphabetical,
+    /// Options used most often and most recently first, then the rest in config order. Usage is
+    /// recorded in a history file (`set rank frecency`)
+    Frecency,
+}
+
+/// A group of flags (by name) that can't be used together. If `required`, exactly one of them
+/// must be given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TabryExclusiveFlags {
+    pub flags: Vec<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// `flag` can only be used if all of the flags in `requires` are also given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TabryFlagRequirement {
+    pub flag: String,
+    pub requires: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,9 +1,7 @@
-// TODO use lazy_static! here
-// https://stackoverflow.com/questions/37405835/populating-a-static-const-with-an-environment-variable-at-runtime-in-rust
-// and actually juts see what people use for logging in rust
-pub fn is_debug() -> bool {
-    match std::env::var("TABRY_DEBUG") {
-        Ok(s) => s != "0" && s != "false",
+/// True if the environment variable is set to something other than "0" or "false".
+pub fn env_flag(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(s) => !s.is_empty() && s != "0" && s != "false",
         Err(_) => false,
     }
 }
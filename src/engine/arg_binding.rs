@@ -0,0 +1,148 @@
+use super::machine_state::NamedArgValue;
+use crate::core::types::TabryConcreteArg;
+use std::collections::HashMap;
+
+/// Work out which declared arg each positional token is bound to, given that there are exactly
+/// `n_tokens` of them. Returns, for each token, the index into `args` (None if there are more
+/// tokens than the args can take).
+///
+/// Required args are always bound. Any tokens left over go to optional args, in order, and then
+/// to varargs (which may be declared anywhere, e.g. `varargs files; arg dest` like `cp`).
+pub fn bind_args(args: &[&TabryConcreteArg], n_tokens: usize) -> Vec<Option<usize>> {
+    let n_required = args.iter().filter(|arg| !arg.optional).count();
+    let mut extra = n_tokens.saturating_sub(n_required);
+
+    let mut result = Vec::with_capacity(n_tokens);
+    for (i, arg) in args.iter().enumerate() {
+        let mut take = if arg.optional { 0 } else { 1 };
+        if arg.varargs {
+            take += extra;
+            extra = 0;
+        } else if arg.optional && extra > 0 {
+            take = 1;
+            extra -= 1;
+        }
+        for _ in 0..take {
+            if result.len() == n_tokens {
+                return result;
+            }
+            result.push(Some(i));
+        }
+    }
+    result.resize(n_tokens, None);
+    result
+}
+
+/// The args the next token (after `n_tokens` already given) could be bound to, in order: the one
+/// it is bound to if more tokens follow, through the one it is bound to if it is the last token.
+/// Optional args and varargs before a required arg mean there can be more than one.
+pub fn possible_args_for_next_token(args: &[&TabryConcreteArg], n_tokens: usize) -> Vec<usize> {
+    // With enough tokens following, every optional arg is filled in order
+    let earliest = bind_args(args, n_tokens + 1 + args.len())[n_tokens];
+    let latest = bind_args(args, n_tokens + 1)[n_tokens];
+    match (earliest, latest) {
+        (Some(earliest), Some(latest)) => (earliest..=latest).collect(),
+        (Some(only), None) | (None, Some(only)) => vec![only],
+        (None, None) => vec![],
+    }
+}
+
+/// Values of named args, given the positional tokens. Varargs get a list of values (possibly
+/// empty); other args only appear if a token was bound to them.
+pub fn named_args(args: &[&TabryConcreteArg], tokens: &[String]) -> HashMap<String, NamedArgValue> {
+    let mut result = HashMap::new();
+    for arg in args {
+        if let (Some(name), true) = (&arg.name, arg.varargs) {
+            result.insert(name.clone(), NamedArgValue::Varargs(vec![]));
+        }
+    }
+    for (token, binding) in tokens.iter().zip(bind_args(args, tokens.len())) {
+        let Some(name) = binding.and_then(|i| args[i].name.as_ref()) else {
+            continue;
+        };
+        match result.get_mut(name) {
+            Some(NamedArgValue::Varargs(values)) => values.push(token.clone()),
+            _ => {
+                result.insert(name.clone(), NamedArgValue::Single(token.clone()));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arg(name: &str, optional: bool, varargs: bool) -> TabryConcreteArg {
+        TabryConcreteArg {
+            name: Some(name.to_owned()),
+            title: None,
+            description: None,
+            options: vec![],
+            optional,
+            varargs,
+        }
+    }
+
+    fn tokens(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_bind_args_fills_optional_args_only_with_extra_tokens() {
+        // [region] name
+        let region = arg("region", true, false);
+        let name = arg("name", false, false);
+        let args = [&region, &name];
+        assert_eq!(bind_args(&args, 1), [Some(1)]);
+        assert_eq!(bind_args(&args, 2), [Some(0), Some(1)]);
+        assert_eq!(bind_args(&args, 3), [Some(0), Some(1), None]);
+    }
+
+    #[test]
+    fn test_bind_args_with_varargs_in_the_middle() {
+        // cp-like: files... dest
+        let files = arg("files", false, true);
+        let dest = arg("dest", false, false);
+        let args = [&files, &dest];
+        assert_eq!(bind_args(&args, 1), [Some(0)]);
+        assert_eq!(bind_args(&args, 2), [Some(0), Some(1)]);
+        assert_eq!(bind_args(&args, 4), [Some(0), Some(0), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn test_possible_args_for_next_token() {
+        let region = arg("region", true, false);
+        let name = arg("name", false, false);
+        assert_eq!(possible_args_for_next_token(&[&region, &name], 0), [0, 1]);
+        assert_eq!(possible_args_for_next_token(&[&region, &name], 1), [1]);
+        assert!(possible_args_for_next_token(&[&region, &name], 2).is_empty());
+
+        let files = arg("files", true, true);
+        let dest = arg("dest", false, false);
+        assert_eq!(possible_args_for_next_token(&[&files, &dest], 0), [0, 1]);
+        assert_eq!(possible_args_for_next_token(&[&files, &dest], 3), [0, 1]);
+    }
+
+    #[test]
+    fn test_named_args() {
+        let env = arg("environment", false, false);
+        let files = arg("files", true, true);
+        let args = [&env, &files];
+        assert_eq!(
+            named_args(&args, &tokens(&["prod"])),
+            HashMap::from([
+                (
+                    "environment".to_owned(),
+                    NamedArgValue::Single("prod".to_owned())
+                ),
+                ("files".to_owned(), NamedArgValue::Varargs(vec![])),
+            ])
+        );
+        assert_eq!(
+            named_args(&args, &tokens(&["prod", "a", "b"]))["files"],
+            NamedArgValue::Varargs(tokens(&["a", "b"]))
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use super::machine_state::NamedArgValue;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Version of the `TABRY_AUTOCOMPLETE_STATE` schema. Fields may be added without changing the
+/// version; it changes if fields are removed or their meaning changes.
+pub const AUTOCOMPLETE_STATE_VERSION: u32 = 1;
+
+/// State passed to `opts shell` commands, as JSON in the `TABRY_AUTOCOMPLETE_STATE` environment
+/// variable. The simpler fields are also available as individual environment variables (see
+/// `env_vars`).
+#[derive(Debug, Serialize)]
+pub struct AutocompleteState<'a> {
+    /// Always `AUTOCOMPLETE_STATE_VERSION`
+    pub version: u32,
+    /// `cmd` from the config, if given
+    pub cmd: Option<&'a str>,
+    /// Subcommands given so far, e.g. `["move", "crash"]`
+    pub subs: &'a [String],
+    /// Flags without an argument that have been given (`false` if negated: `--no-foo`)
+    pub flags: &'a HashMap<String, bool>,
+    /// Arguments of flags that take one (the last one given, for repeatable flags)
+    pub flag_args: &'a HashMap<String, String>,
+    /// `flags` and `flag_args` merged, like the `flags` of the Ruby version of tabry
+    pub all_flags: HashMap<&'a str, serde_json::Value>,
+    /// Number of times each repeatable flag has been given
+    pub flag_counts: &'a HashMap<String, usize>,
+    /// All arguments given for each repeatable flag that takes one
+    pub flag_arg_lists: &'a HashMap<String, Vec<String>>,
+    /// Positional args given so far (not including the token being completed)
+    pub args: &'a [String],
+    /// `args` by name, assuming the token being completed is another arg
+    pub named_args: HashMap<String, NamedArgValue>,
+    /// Whether `--` has been given
+    pub dashdash: bool,
+    /// The token being completed, as typed so far
+    pub prefix: &'a str,
+    /// The flag whose argument is being completed, if completing a flag argument
+    pub current_flag: Option<&'a str>,
+    /// Index (into `args`) of the arg being completed, if completing a positional arg
+    pub arg_index: Option<usize>,
+    /// Name of the arg whose options are being found, if it has one
+    pub arg_name: Option<&'a str>,
+    /// The whole command line being completed, if known
+    pub compline: Option<&'a str>,
+    /// At most this many options will be offered (`set max-results`), so there is no need to
+    /// output more
+    pub max_results: Option<usize>,
+}
+
+impl AutocompleteState<'_> {
+    /// Environment variables for scripts that don't want to parse JSON. Values not applicable
+    /// (e.g. `TABRY_CURRENT_FLAG` when completing an arg) are empty.
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let opt_to_string = |s: Option<&str>| s.unwrap_or("").to_owned();
+        vec![
+            ("TABRY_STATE_VERSION", self.version.to_string()),
+            ("TABRY_CMD", opt_to_string(self.cmd)),
+            ("TABRY_SUBS", self.subs.join(" ")),
+            ("TABRY_PREFIX", self.prefix.to_owned()),
+            ("TABRY_CURRENT_FLAG", opt_to_string(self.current_flag)),
+            (
+                "TABRY_ARG_INDEX",
+                self.arg_index.map(|i| i.to_string()).unwrap_or_default(),
+            ),
+            ("TABRY_ARG_NAME", opt_to_string(self.arg_name)),
+            (
+                "TABRY_DASHDASH",
+                if self.dashdash { "1" } else { "" }.to_owned(),
+            ),
+            ("TABRY_COMPLINE", opt_to_string(self.compline)),
+            (
+                "TABRY_MAX_RESULTS",
+                self.max_results.map(|n| n.to_string()).unwrap_or_default(),
+            ),
+        ]
+    }
+}
+
+/// Merge flags and flag args into one map of flag name to `true`/`false`/argument.
+pub fn merge_flags<'a>(
+    flags: &'a HashMap<String, bool>,
+    flag_args: &'a HashMap<String, String>,
+) -> HashMap<&'a str, serde_json::Value> {
+    let mut result: HashMap<&str, serde_json::Value> = flags
+        .iter()
+        .map(|(k, v)| (k.as_str(), (*v).into()))
+        .collect();
+    for (k, v) in flag_args {
+        result.insert(k.as_str(), v.as_str().into());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_json_diff::assert_json_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_representation_and_env_vars() {
+        let subs = vec!["move".to_owned(), "crash".to_owned()];
+        let flags = HashMap::from([("dry-run".to_owned(), true)]);
+        let flag_args = HashMap::from([("speed".to_owned(), "fast".to_owned())]);
+        let args = vec!["car".to_owned()];
+        let state = AutocompleteState {
+            version: AUTOCOMPLETE_STATE_VERSION,
+            cmd: Some("vehicles"),
+            subs: &subs,
+            flags: &flags,
+            flag_args: &flag_args,
+            all_flags: merge_flags(&flags, &flag_args),
+            flag_counts: &HashMap::new(),
+            flag_arg_lists: &HashMap::new(),
+            args: &args,
+            named_args: HashMap::from([(
+                "vehicle-type".to_owned(),
+                NamedArgValue::Single("car".to_owned()),
+            )]),
+            dashdash: false,
+            prefix: "bi",
+            current_flag: None,
+            arg_index: Some(1),
+            arg_name: Some("crash-into-vehicle"),
+            compline: Some("vehicles move crash --dry-run --speed fast car bi"),
+            max_results: None,
+        };
+
+        assert_json_eq!(
+            serde_json::to_value(&state).unwrap(),
+            json!({
+                "version": 1,
+                "cmd": "vehicles",
+                "subs": ["move", "crash"],
+                "flags": {"dry-run": true},
+                "flag_args": {"speed": "fast"},
+                "all_flags": {"dry-run": true, "speed": "fast"},
+                "flag_counts": {},
+                "flag_arg_lists": {},
+                "args": ["car"],
+                "named_args": {"vehicle-type": "car"},
+                "dashdash": false,
+                "prefix": "bi",
+                "current_flag": null,
+                "arg_index": 1,
+                "arg_name": "crash-into-vehicle",
+                "compline": "vehicles move crash --dry-run --speed fast car bi",
+                "max_results": null
+            })
+        );
+
+        let env_vars = HashMap::<_, _>::from_iter(state.env_vars());
+        assert_eq!(env_vars["TABRY_SUBS"], "move crash");
+        assert_eq!(env_vars["TABRY_PREFIX"], "bi");
+        assert_eq!(env_vars["TABRY_CURRENT_FLAG"], "");
+        assert_eq!(env_vars["TABRY_ARG_INDEX"], "1");
+        assert_eq!(env_vars["TABRY_ARG_NAME"], "crash-into-vehicle");
+        assert_eq!(env_vars["TABRY_DASHDASH"], "");
+    }
+}
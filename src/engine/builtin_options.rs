@@ -0,0 +1,406 @@
+/// Options for `opts builtin <name>`: common sources of options, found natively rather than by
+/// running shell commands. Sources which can't be read (e.g. no /proc, or not in a git
+/// repository) just give no options; it's never worth failing a completion over.
+///
+/// The functions reading from files take the path (or the text) so they can be tested against
+/// fixtures; `builtin_options` uses the real locations.
+use crate::core::types::TabryBuiltin;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct BuiltinOption {
+    pub value: String,
+    pub desc: Option<String>,
+}
+
+impl BuiltinOption {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            desc: None,
+        }
+    }
+
+    pub fn with_desc(value: impl Into<String>, desc: impl Into<String>) -> Self {
+        let desc = desc.into();
+        Self {
+            value: value.into(),
+            desc: if desc.is_empty() { None } else { Some(desc) },
+        }
+    }
+}
+
+const SIGNALS: [(&str, &str); 31] = [
+    ("HUP", "Hangup"),
+    ("INT", "Interrupt"),
+    ("QUIT", "Quit"),
+    ("ILL", "Illegal instruction"),
+    ("TRAP", "Trace/breakpoint trap"),
+    ("ABRT", "Aborted"),
+    ("BUS", "Bus error"),
+    ("FPE", "Floating point exception"),
+    ("KILL", "Killed"),
+    ("USR1", "User defined signal 1"),
+    ("SEGV", "Segmentation fault"),
+    ("USR2", "User defined signal 2"),
+    ("PIPE", "Broken pipe"),
+    ("ALRM", "Alarm clock"),
+    ("TERM", "Terminated"),
+    ("STKFLT", "Stack fault"),
+    ("CHLD", "Child exited"),
+    ("CONT", "Continued"),
+    ("STOP", "Stopped (signal)"),
+    ("TSTP", "Stopped"),
+    ("TTIN", "Stopped (tty input)"),
+    ("TTOU", "Stopped (tty output)"),
+    ("URG", "Urgent I/O condition"),
+    ("XCPU", "CPU time limit exceeded"),
+    ("XFSZ", "File size limit exceeded"),
+    ("VTALRM", "Virtual timer expired"),
+    ("PROF", "Profiling timer expired"),
+    ("WINCH", "Window changed"),
+    ("IO", "I/O possible"),
+    ("PWR", "Power failure"),
+    ("SYS", "Bad system call"),
+];
+
+pub fn builtin_options(builtin: TabryBuiltin) -> Vec<BuiltinOption> {
+    match builtin {
+        TabryBuiltin::EnvVars => env_vars(),
+        TabryBuiltin::Users => users(&read_or_empty("/etc/passwd")),
+        TabryBuiltin::Groups => groups(&read_or_empty("/etc/group")),
+        TabryBuiltin::Hosts => {
+            let mut hosts = match std::env::var_os("HOME") {
+                Some(home) => {
+                    ssh_config_hosts(&read_or_empty(Path::new(&home).join(".ssh/config")))
+                }
+                None => vec![],
+            };
+            hosts.extend(etc_hosts(&read_or_empty("/etc/hosts")));
+            hosts
+        }
+        TabryBuiltin::Pids => pids(Path::new("/proc")),
+        TabryBuiltin::Processes => processes(Path::new("/proc")),
+        TabryBuiltin::GitBranches => git_options(|dir| git_refs(dir, "heads")),
+        TabryBuiltin::GitTags => git_options(|dir| git_refs(dir, "tags")),
+        TabryBuiltin::GitRemotes => git_options(git_remotes),
+        TabryBuiltin::Signals => SIGNALS
+            .iter()
+            .map(|(name, desc)| BuiltinOption::with_desc(*name, *desc))
+            .collect(),
+    }
+}
+
+fn read_or_empty(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+fn git_options(f: impl Fn(&Path) -> Vec<String>) -> Vec<BuiltinOption> {
+    let Some(git_dir) = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| find_git_dir(&cwd))
+    else {
+        return vec![];
+    };
+    f(&git_dir).into_iter().map(BuiltinOption::new).collect()
+}
+
+fn env_vars() -> Vec<BuiltinOption> {
+    let mut names: Vec<String> = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .collect();
+    names.sort();
+    names.into_iter().map(BuiltinOption::new).collect()
+}
+
+/// Non-comment lines of a colon-separated file like /etc/passwd, split into fields.
+fn colon_separated_lines(text: &str) -> impl Iterator<Item = Vec<&str>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').collect())
+}
+
+/// Usernames from the text of /etc/passwd, described by their GECOS (full name) field.
+pub fn users(passwd: &str) -> Vec<BuiltinOption> {
+    colon_separated_lines(passwd)
+        .map(|fields| {
+            let gecos = fields.get(4).and_then(|g| g.split(',').next());
+            BuiltinOption::with_desc(fields[0], gecos.unwrap_or(""))
+        })
+        .collect()
+}
+
+/// Group names from the text of /etc/group.
+pub fn groups(group: &str) -> Vec<BuiltinOption> {
+    colon_separated_lines(group)
+        .map(|fields| BuiltinOption::new(fields[0]))
+        .collect()
+}
+
+/// Hostnames (and aliases) from the text of /etc/hosts, described by their address.
+pub fn etc_hosts(hosts: &str) -> Vec<BuiltinOption> {
+    let mut result = vec![];
+    for line in hosts.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        result.extend(fields.map(|name| BuiltinOption::with_desc(name, address)));
+    }
+    result
+}
+
+/// Hosts from the `Host` lines of an ssh config file. Patterns (e.g. `*.example.com` or
+/// `!bastion`) are skipped, as they aren't something you can connect to.
+pub fn ssh_config_hosts(ssh_config: &str) -> Vec<BuiltinOption> {
+    let mut result = vec![];
+    for line in ssh_config.lines() {
+        let line = line.trim();
+        let Some((keyword, rest)) = line.split_once(|c: char| c.is_whitespace() || c == '=') else {
+            continue;
+        };
+        if !keyword.eq_ignore_ascii_case("host") {
+            continue;
+        }
+        result.extend(
+            rest.split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|host| !host.is_empty() && !host.contains(['*', '?', '!']))
+                .map(BuiltinOption::new),
+        );
+    }
+    result
+}
+
+/// Running processes from a procfs directory: (pid, command name), ordered by pid.
+fn proc_entries(proc_dir: &Path) -> Vec<(u32, String)> {
+    let Ok(entries) = fs::read_dir(proc_dir) else {
+        return vec![];
+    };
+    let mut result: Vec<(u32, String)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+            Some((pid, comm.trim_end().to_owned()))
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+/// Process IDs from a procfs directory, described by their command name.
+pub fn pids(proc_dir: &Path) -> Vec<BuiltinOption> {
+    proc_entries(proc_dir)
+        .into_iter()
+        .map(|(pid, comm)| BuiltinOption::with_desc(pid.to_string(), comm))
+        .collect()
+}
+
+/// Names of running processes from a procfs directory, without duplicates.
+pub fn processes(proc_dir: &Path) -> Vec<BuiltinOption> {
+    let mut names: Vec<String> = proc_entries(proc_dir)
+        .into_iter()
+        .map(|(_, comm)| comm)
+        .collect();
+    names.sort();
+    names.dedup();
+    names.into_iter().map(BuiltinOption::new).collect()
+}
+
+/// Find the git directory for a working directory, looking in `start` and then its parents.
+/// Handles `.git` files (`gitdir: ...`) as used by worktrees and submodules.
+pub fn find_git_dir(start: &Path) -> Option<PathBuf> {
+    for dir in start.ancestors() {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        if dot_git.is_file() {
+            let contents = fs::read_to_string(&dot_git).ok()?;
+            let git_dir = contents.trim().strip_prefix("gitdir:")?.trim();
+            return Some(dir.join(git_dir));
+        }
+    }
+    None
+}
+
+/// The directory holding refs and config: for a worktree, that of the main repository.
+fn git_common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim()),
+        Err(_) => git_dir.to_owned(),
+    }
+}
+
+fn collect_loose_refs(dir: &Path, prefix: &str, result: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let path = entry.path();
+        if path.is_dir() {
+            collect_loose_refs(&path, &format!("{prefix}{name}/"), result);
+        } else {
+            result.push(format!("{prefix}{name}"));
+        }
+    }
+}
+
+/// Names of refs under `refs/<kind>/` (e.g. "heads" for branches), both loose and packed,
+/// sorted.
+pub fn git_refs(git_dir: &Path, kind: &str) -> Vec<String> {
+    let common_dir = git_common_dir(git_dir);
+    let mut result = vec![];
+    collect_loose_refs(&common_dir.join("refs").join(kind), "", &mut result);
+
+    let packed_prefix = format!("refs/{kind}/");
+    for line in read_or_empty(common_dir.join("packed-refs")).lines() {
+        // "<sha> <ref>"; comments start with '#' and peeled tags with '^'
+        if let Some(name) = line
+            .split_once(' ')
+            .and_then(|(_, r)| r.strip_prefix(&packed_prefix))
+        {
+            result.push(name.to_owned());
+        }
+    }
+
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// Names of remotes from a git directory's config (`[remote "origin"]` sections).
+pub fn git_remotes(git_dir: &Path) -> Vec<String> {
+    read_or_empty(git_common_dir(git_dir).join("config"))
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("[remote \"")?
+                .strip_suffix("\"]")
+                .map(str::to_owned)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::load_fixture_file_text;
+
+    fn values(options: &[BuiltinOption]) -> Vec<&str> {
+        options.iter().map(|o| o.value.as_str()).collect()
+    }
+
+    /// A fresh directory under the temp dir, for building fake git repos and procfs trees.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "tabry-builtin-options-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: PathBuf, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_users_and_groups() {
+        let users = users(&load_fixture_file_text("builtins/passwd"));
+        assert_eq!(values(&users), ["root", "daemon", "alice"]);
+        assert_eq!(users[2].desc.as_deref(), Some("Alice Example"));
+        assert_eq!(users[1].desc, None);
+
+        let groups = groups(&load_fixture_file_text("builtins/group"));
+        assert_eq!(values(&groups), ["root", "wheel", "developers"]);
+    }
+
+    #[test]
+    fn test_hosts() {
+        let hosts = etc_hosts(&load_fixture_file_text("builtins/hosts"));
+        assert_eq!(
+            values(&hosts),
+            ["localhost", "ip6-localhost", "buildbox", "buildbox.lan"]
+        );
+        assert_eq!(hosts[2].desc.as_deref(), Some("192.168.1.10"));
+
+        let ssh_hosts = ssh_config_hosts(&load_fixture_file_text("builtins/ssh_config"));
+        assert_eq!(values(&ssh_hosts), ["github.com", "prod-db", "staging-db"]);
+    }
+
+    #[test]
+    fn test_pids_and_processes() {
+        let proc_dir = temp_dir("proc");
+        write(proc_dir.join("42/comm"), "bash\n");
+        write(proc_dir.join("7/comm"), "sshd\n");
+        write(proc_dir.join("100/comm"), "bash\n");
+        write(proc_dir.join("self/comm"), "tabry\n");
+        write(proc_dir.join("uptime"), "1.0 1.0\n");
+
+        let pids = pids(&proc_dir);
+        assert_eq!(values(&pids), ["7", "42", "100"]);
+        assert_eq!(pids[0].desc.as_deref(), Some("sshd"));
+        assert_eq!(values(&processes(&proc_dir)), ["bash", "sshd"]);
+
+        fs::remove_dir_all(proc_dir).unwrap();
+    }
+
+    #[test]
+    fn test_git_refs_and_remotes() {
+        let repo = temp_dir("git");
+        let git_dir = repo.join(".git");
+        write(git_dir.join("refs/heads/main"), "0123\n");
+        write(git_dir.join("refs/heads/feature/wheels"), "4567\n");
+        write(git_dir.join("refs/tags/v1.0"), "89ab\n");
+        write(
+            git_dir.join("packed-refs"),
+            "# pack-refs with: peeled fully-peeled sorted\n\
+             cdef refs/heads/main\n\
+             cdef refs/heads/old-branch\n\
+             cdef refs/remotes/origin/main\n\
+             cdef refs/tags/v0.9\n\
+             ^0123\n",
+        );
+        write(
+            git_dir.join("config"),
+            "[core]\n\tbare = false\n[remote \"origin\"]\n\turl = x\n[remote \"upstream\"]\n",
+        );
+        fs::create_dir_all(repo.join("src/deep")).unwrap();
+
+        let found = find_git_dir(&repo.join("src/deep")).unwrap();
+        assert_eq!(found, git_dir);
+        assert_eq!(
+            git_refs(&found, "heads"),
+            ["feature/wheels", "main", "old-branch"]
+        );
+        assert_eq!(git_refs(&found, "tags"), ["v0.9", "v1.0"]);
+        assert_eq!(git_remotes(&found), ["origin", "upstream"]);
+
+        // a worktree: .git file pointing to a git dir with a commondir
+        let worktree = repo.join("worktree");
+        write(worktree.join(".git"), "gitdir: ../.git/worktrees/wt\n");
+        write(git_dir.join("worktrees/wt/commondir"), "../..\n");
+        let found = find_git_dir(&worktree).unwrap();
+        assert_eq!(git_remotes(&found), ["origin", "upstream"]);
+        assert_eq!(git_refs(&found, "tags"), ["v0.9", "v1.0"]);
+
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_signals() {
+        let signals = builtin_options(TabryBuiltin::Signals);
+        assert!(values(&signals).contains(&"TERM"));
+        assert_eq!(signals[0].desc.as_deref(), Some("Hangup"));
+    }
+}
@@ -1,9 +1,12 @@
+use std::fmt;
 use std::mem::swap;
+use tracing::{debug, trace};
 
 use crate::core::config::TabryConf;
 use crate::core::config::TabryConfError;
-use crate::core::util::is_debug;
+use crate::core::types::{TabrySettings, TabrySubMatching};
 
+use super::arg_binding;
 use super::machine_state::{MachineState, MachineStateMode};
 use super::token_matching::TokenMatching;
 
@@ -14,7 +17,47 @@ use super::result::TabryResult;
 pub struct Machine {
     config: TabryConf,
     pub state: MachineState,
-    log: bool,
+}
+
+/// What the machine took a token to be (for debugging)
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenMatch {
+    Subcommand(String),
+    Dashdash,
+    /// A flag without an argument
+    Flag(String),
+    /// A negated flag ("--no-foo")
+    NegatedFlag(String),
+    /// A flag whose argument is the next token
+    FlagWithArg(String),
+    /// The argument of a flag
+    FlagArg(String),
+    Help,
+    /// A positional arg, and the name of the declared arg it is bound to (for now: later tokens
+    /// can change that), if any
+    Arg(Option<String>),
+    /// The first token of another command line (an `opts command` arg)
+    CommandStart,
+    /// A token in another command line
+    CommandArg,
+}
+
+impl fmt::Display for TokenMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenMatch::Subcommand(name) => write!(f, "subcommand {}", name),
+            TokenMatch::Dashdash => write!(f, "-- (the rest are args)"),
+            TokenMatch::Flag(name) => write!(f, "flag {}", name),
+            TokenMatch::NegatedFlag(name) => write!(f, "negated flag {}", name),
+            TokenMatch::FlagWithArg(name) => write!(f, "flag {}, taking the next token", name),
+            TokenMatch::FlagArg(name) => write!(f, "argument of flag {}", name),
+            TokenMatch::Help => write!(f, "help"),
+            TokenMatch::Arg(Some(name)) => write!(f, "arg {}", name),
+            TokenMatch::Arg(None) => write!(f, "arg (not declared)"),
+            TokenMatch::CommandStart => write!(f, "start of command line"),
+            TokenMatch::CommandArg => write!(f, "in command line"),
+        }
+    }
 }
 
 impl Machine {
@@ -22,7 +65,6 @@ impl Machine {
         Machine {
             config,
             state: MachineState::default(),
-            log: is_debug(),
         }
     }
 
@@ -35,30 +77,36 @@ impl Machine {
         Ok(this.to_result())
     }
 
-    /// Feed the state machine one token.
-    pub fn next(&mut self, token: &String) -> Result<(), TabryConfError> {
-        match self.state.mode {
-            MachineStateMode::Subcommand => self.match_mode_subcommand(token),
-            MachineStateMode::Flagarg { .. } => {
-                self.match_mode_flagarg(token);
-                Ok(())
-            }
-        }
+    /// Feed the state machine one token, returning what it was taken to be.
+    pub fn next(&mut self, token: &String) -> Result<TokenMatch, TabryConfError> {
+        let found = match self.state.mode {
+            MachineStateMode::Subcommand => self.match_mode_subcommand(token)?,
+            MachineStateMode::Flagarg { .. } => self.match_mode_flagarg(token)?,
+            // Everything is part of the other command line, even things that look like flags
+            MachineStateMode::Command { .. } => self.match_arg(token)?,
+        };
+        debug!("token {:?}: {}", token, found);
+        trace!("state: {:?}", self.state);
+        Ok(found)
     }
 
-    fn match_mode_subcommand(&mut self, token: &String) -> Result<(), TabryConfError> {
-        if self.match_subcommand(token)?
-            || self.match_dashdash(token)
-            || self.match_flag(token)?
-            || self.match_help(token)
-        {
-            Ok(())
-        } else {
-            // Fallback -- machine treats anything unrecognized as an arg.
-            // If the command doesn't take the required numbers of arguments,
-            // that will be determined later.
-            self.match_arg(token)
+    fn match_mode_subcommand(&mut self, token: &String) -> Result<TokenMatch, TabryConfError> {
+        if let Some(found) = self.match_subcommand(token)? {
+            return Ok(found);
+        }
+        if let Some(found) = self.match_dashdash(token) {
+            return Ok(found);
+        }
+        if let Some(found) = self.match_flag(token)? {
+            return Ok(found);
         }
+        if let Some(found) = self.match_help(token) {
+            return Ok(found);
+        }
+        // Fallback -- machine treats anything unrecognized as an arg.
+        // If the command doesn't take the required numbers of arguments,
+        // that will be determined later.
+        self.match_arg(token)
     }
 
     /*
@@ -68,94 +116,155 @@ impl Machine {
     }
     */
 
-    fn match_subcommand(&mut self, token: &String) -> Result<bool, TabryConfError> {
+    fn match_subcommand(&mut self, token: &String) -> Result<Option<TokenMatch>, TabryConfError> {
         if !self.state.args.is_empty() {
-            return Ok(false);
+            return Ok(None);
         }
 
         // TODO using self.current_sub() causes weird borrow problem. But also want t
         // make self.find_in_subs etc. be able to mutate self which it can't right now
         // due to weird lifetime problem.
-        let sub_here = self.config.dig_sub(&self.state.subcommand_stack)?;
+        let subs_here = self.config.dig_subs(&self.state.subcommand_stack)?;
+        let sub_here = subs_here.last().unwrap();
+        let settings = TabrySettings::resolve(subs_here.iter().copied());
+
+        let ignore_case = settings.case_insensitive.unwrap_or(false);
+
+        let mut found = self.config.find_in_subs(&sub_here.subs, token, true)?;
+        if found.is_none() && ignore_case {
+            found = self
+                .config
+                .find_in_subs_ignoring_case(&sub_here.subs, token)?;
+        }
+        if found.is_none() && settings.sub_matching == Some(TabrySubMatching::Prefix) {
+            found =
+                self.config
+                    .find_in_subs_by_unique_prefix(&sub_here.subs, token, ignore_case)?;
+        }
 
-        if let Some(sub) = self.config.find_in_subs(&sub_here.subs, token, true)? {
+        if let Some(sub) = found {
             let name = TabryConf::unwrap_sub_name(sub)?;
             self.state.subcommand_stack.push(name.to_owned());
-            self.log(format!("STEP subcommand, add {}", name));
-            Ok(true)
+            Ok(Some(TokenMatch::Subcommand(name.to_owned())))
         } else {
-            Ok(false)
+            Ok(None)
         }
     }
 
-    fn match_dashdash(&mut self, token: &String) -> bool {
+    fn match_dashdash(&mut self, token: &String) -> Option<TokenMatch> {
         if !self.state.dashdash && token == "--" {
             self.state.dashdash = true;
-            true
+            Some(TokenMatch::Dashdash)
         } else {
-            false
+            None
         }
     }
 
-    fn match_flag(&mut self, token: &str) -> Result<bool, TabryConfError> {
+    fn match_flag(&mut self, token: &str) -> Result<Option<TokenMatch>, TabryConfError> {
         if self.state.dashdash {
-            return Ok(false);
+            return Ok(None);
         }
 
+        let subs = self.config.dig_subs(&self.state.subcommand_stack)?;
+        let ignore_case = TabrySettings::resolve(subs.iter().copied())
+            .case_insensitive
+            .unwrap_or(false);
+
         // Check flags for each Subcommand in stack, starting with the most specific Subcommand.
-        for sub in self
-            .config
-            .dig_subs(&self.state.subcommand_stack)?
-            .iter()
-            .rev()
-        {
+        for sub in subs.iter().rev() {
             for flag in self.config.expand_flags(&sub.flags) {
-                if flag.match_token(token) {
+                if flag.match_token_with_case(token, ignore_case) {
                     if flag.arg {
                         self.state.mode = MachineStateMode::Flagarg {
                             current_flag: flag.name.clone(),
-                        }
-                    } else {
-                        self.state.flags.insert(flag.name.clone(), true);
+                        };
+                        return Ok(Some(TokenMatch::FlagWithArg(flag.name.clone())));
                     }
-                    return Ok(true);
+                    self.state.flags.insert(flag.name.clone(), true);
+                    if flag.repeatable {
+                        *self.state.flag_counts.entry(flag.name.clone()).or_default() += 1;
+                    }
+                    return Ok(Some(TokenMatch::Flag(flag.name.clone())));
+                }
+                if flag.match_negated_token_with_case(token, ignore_case) {
+                    self.state.flags.insert(flag.name.clone(), false);
+                    return Ok(Some(TokenMatch::NegatedFlag(flag.name.clone())));
                 }
             }
         }
 
-        Ok(false)
+        Ok(None)
     }
 
-    fn match_help(&mut self, token: &str) -> bool {
+    fn match_help(&mut self, token: &str) -> Option<TokenMatch> {
         if !self.state.dashdash && (token == "help" || token == "--help" || token == "-?") {
             self.state.help = true;
-            true
+            Some(TokenMatch::Help)
         } else {
-            false
+            None
         }
     }
 
-    fn match_arg(&mut self, token: &String) -> Result<(), TabryConfError> {
-        self.log(format!("STEP fell back to argument {:?}", token));
-        self.state.args.push(token.clone());
-        Ok(())
+    fn match_arg(&mut self, token: &str) -> Result<TokenMatch, TabryConfError> {
+        self.state.args.push(token.to_owned());
+
+        // Adding a token can change which arg earlier tokens are bound to (e.g. an optional arg
+        // before a required one), so bind them all again
+        let subs = self.config.dig_subs(&self.state.subcommand_stack)?;
+        let sub_args = self
+            .config
+            .expand_args(&subs.last().unwrap().args)
+            .collect::<Vec<_>>();
+        self.state.named_args = arg_binding::named_args(&sub_args, &self.state.args);
+
+        if self.state.mode != MachineStateMode::Subcommand {
+            return Ok(TokenMatch::CommandArg);
+        }
+        // The first token of an `opts command` arg starts another command line
+        let n_args = self.state.args.len();
+        let binding = arg_binding::bind_args(&sub_args, n_args)[n_args - 1];
+        if binding.is_some_and(|i| sub_args[i].takes_command()) {
+            self.state.mode = MachineStateMode::Command {
+                first_arg: n_args - 1,
+            };
+            return Ok(TokenMatch::CommandStart);
+        }
+        Ok(TokenMatch::Arg(
+            binding.and_then(|i| sub_args[i].name.clone()),
+        ))
     }
 
-    fn match_mode_flagarg(&mut self, token: &str) {
+    fn match_mode_flagarg(&mut self, token: &str) -> Result<TokenMatch, TabryConfError> {
         // Set mode to subcommand and put string in flag_args
         let mut mode = MachineStateMode::Subcommand;
         swap(&mut mode, &mut self.state.mode);
-        if let MachineStateMode::Flagarg { current_flag } = mode {
-            self.state.flag_args.insert(current_flag, token.to_owned());
-        } else {
+        let MachineStateMode::Flagarg { current_flag } = mode else {
             unreachable!();
+        };
+        if self.flag_is_repeatable(&current_flag)? {
+            self.state
+                .flag_arg_lists
+                .entry(current_flag.clone())
+                .or_default()
+                .push(token.to_owned());
         }
+        self.state
+            .flag_args
+            .insert(current_flag.clone(), token.to_owned());
+        Ok(TokenMatch::FlagArg(current_flag))
     }
 
-    fn log(&self, msg: String) {
-        if self.log {
-            println!("{}; current state: {:?}", msg, self.state);
+    fn flag_is_repeatable(&self, name: &str) -> Result<bool, TabryConfError> {
+        for sub in self.config.dig_subs(&self.state.subcommand_stack)? {
+            if let Some(flag) = self
+                .config
+                .expand_flags(&sub.flags)
+                .find(|f| f.name == name)
+            {
+                return Ok(flag.repeatable);
+            }
         }
+        Ok(false)
     }
 
     /// Call this after machine is done to morph into a result
@@ -208,8 +317,17 @@ mod tests {
     // but I don't know how to do that with the current test framework
     #[test]
     fn test_all_expectations() {
-        // load fixture files
         let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        check_all_expectations(tabry_conf);
+    }
+
+    #[test]
+    fn test_all_expectations_with_linked_config() {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        check_all_expectations(tabry_conf.link().unwrap());
+    }
+
+    fn check_all_expectations(tabry_conf: TabryConf) {
         let expectations: serde_json::Value = load_fixture_file("vehicles-expectations.json");
 
         // TODO figure out how to use name. use a macro here?
@@ -232,6 +350,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_token_matches() {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        let mut machine = Machine::new(tabry_conf);
+        let tokens = [
+            "move", "convoy", "-v", "--size", "3", "car", "vehicles", "--size",
+        ];
+        let matches: Vec<TokenMatch> = tokens
+            .iter()
+            .map(|token| machine.next(&token.to_string()).unwrap())
+            .collect();
+        assert_eq!(
+            matches,
+            [
+                TokenMatch::Subcommand("move".to_owned()),
+                TokenMatch::Subcommand("convoy".to_owned()),
+                TokenMatch::Flag("verbose".to_owned()),
+                TokenMatch::FlagWithArg("size".to_owned()),
+                TokenMatch::FlagArg("size".to_owned()),
+                TokenMatch::Arg(Some("vehicle-type".to_owned())),
+                TokenMatch::CommandStart,
+                TokenMatch::CommandArg,
+            ]
+        );
+    }
+
     #[test]
     fn test_missing_include() {
         let tabry_conf: TabryConf = load_fixture_file("missing_include.json");
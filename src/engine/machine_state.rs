@@ -1,4 +1,5 @@
 use serde::ser::{Serialize, SerializeStruct};
+use serde::Serialize as DeriveSerialize;
 use std::collections::HashMap;
 use std::fmt;
 
@@ -9,6 +10,11 @@ pub enum MachineStateMode {
     Flagarg {
         current_flag: String,
     },
+    /// Given an arg with `opts command`: the rest of the tokens are another command line, which
+    /// starts at `args[first_arg]`
+    Command {
+        first_arg: usize,
+    },
 }
 
 impl From<&MachineStateMode> for String {
@@ -16,45 +22,86 @@ impl From<&MachineStateMode> for String {
         match mode {
             MachineStateMode::Subcommand => "Subcommand".to_string(),
             MachineStateMode::Flagarg { current_flag } => format!("Flagarg({})", current_flag),
+            MachineStateMode::Command { first_arg } => format!("Command({})", first_arg),
         }
     }
 }
 
+/// Value(s) given for a named arg: a string, or a list for varargs.
+#[derive(Debug, Clone, PartialEq, DeriveSerialize)]
+#[serde(untagged)]
+pub enum NamedArgValue {
+    Single(String),
+    Varargs(Vec<String>),
+}
+
 #[derive(Default)]
 pub struct MachineState {
     pub mode: MachineStateMode,
     pub subcommand_stack: Vec<String>,
     pub flags: HashMap<String, bool>,
     pub flag_args: HashMap<String, String>,
+    /// Number of times each repeatable flag (without an argument) has been given
+    pub flag_counts: HashMap<String, usize>,
+    /// All values given for each repeatable flagarg, in order (flag_args has only the last)
+    pub flag_arg_lists: HashMap<String, Vec<String>>,
     pub args: Vec<String>,
+    /// Args by name, according to which declared arg each of `args` is bound to (see
+    /// `arg_binding::bind_args`)
+    pub named_args: HashMap<String, NamedArgValue>,
     pub help: bool,
     pub dashdash: bool,
 }
 
+impl MachineState {
+    /// In `Command` mode, the command line given for an `opts command` arg so far: the command
+    /// and its args.
+    pub fn command_args(&self) -> Option<&[String]> {
+        match self.mode {
+            MachineStateMode::Command { first_arg } => Some(&self.args[first_arg..]),
+            _ => None,
+        }
+    }
+
+    /// Whether a flag (with or without an argument) has been given. A negated flag ("--no-foo")
+    /// doesn't count as given.
+    pub fn flag_given(&self, name: &str) -> bool {
+        self.flags.get(name) == Some(&true) || self.flag_args.contains_key(name)
+    }
+}
+
 impl Serialize for MachineState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut n_fields = 7;
+        let mut n_fields = 10;
         // TODO this seems manual (have to check twice) and rest of this function
         // feels very repetitive
-        if let MachineStateMode::Flagarg { .. } = self.mode {
+        if self.mode != MachineStateMode::Subcommand {
             n_fields += 1;
         }
 
         let mut state = serializer.serialize_struct("MachineState", n_fields)?;
 
-        if let MachineStateMode::Flagarg { current_flag } = &self.mode {
-            state.serialize_field("mode", "flagarg")?;
-            state.serialize_field("current_flag", current_flag)?;
-        } else {
-            state.serialize_field("mode", "subcommand")?;
+        match &self.mode {
+            MachineStateMode::Subcommand => state.serialize_field("mode", "subcommand")?,
+            MachineStateMode::Flagarg { current_flag } => {
+                state.serialize_field("mode", "flagarg")?;
+                state.serialize_field("current_flag", current_flag)?;
+            }
+            MachineStateMode::Command { .. } => {
+                state.serialize_field("mode", "command")?;
+                state.serialize_field("command_args", &self.command_args())?;
+            }
         }
 
         state.serialize_field("flags", &self.flags)?;
         state.serialize_field("flag_args", &self.flag_args)?;
+        state.serialize_field("flag_counts", &self.flag_counts)?;
+        state.serialize_field("flag_arg_lists", &self.flag_arg_lists)?;
         state.serialize_field("args", &self.args)?;
+        state.serialize_field("named_args", &self.named_args)?;
         state.serialize_field("help", &self.help)?;
         state.serialize_field("dashdash", &self.dashdash)?;
         state.serialize_field("subs", &self.subcommand_stack)?;
@@ -81,9 +128,18 @@ impl fmt::Debug for MachineState {
         if !(self.flag_args == Default::default()) {
             res = res.field("flag_args", &self.flag_args);
         }
+        if !(self.flag_counts == Default::default()) {
+            res = res.field("flag_counts", &self.flag_counts);
+        }
+        if !(self.flag_arg_lists == Default::default()) {
+            res = res.field("flag_arg_lists", &self.flag_arg_lists);
+        }
         if !self.args.is_empty() {
             res = res.field("args", &self.args);
         }
+        if !self.named_args.is_empty() {
+            res = res.field("named_args", &self.named_args);
+        }
         if self.help {
             res = res.field("help", &self.help);
         }
@@ -105,6 +161,10 @@ mod tests {
             help: true,
             flag_args: HashMap::from([("foo".to_owned(), "bar".to_owned())]),
             args: vec!["myarg".to_owned()],
+            named_args: HashMap::from([(
+                "thing".to_owned(),
+                NamedArgValue::Single("myarg".to_owned()),
+            )]),
             ..Default::default()
         };
         let actual = serde_json::value::to_value(state);
@@ -115,9 +175,12 @@ mod tests {
               "help": true,
               "dashdash": false,
               "flag_args": {"foo":"bar"},
+              "flag_counts": {},
+              "flag_arg_lists": {},
               "flags": {},
               "mode": "subcommand",
-              "args": ["myarg"]
+              "args": ["myarg"],
+              "named_args": {"thing": "myarg"}
           }
         "#,
         );
@@ -1,4 +1,7 @@
 // see lib.rs for hierarchy description
+pub mod arg_binding;
+pub mod autocomplete_state;
+pub mod builtin_options;
 pub mod machine;
 pub mod machine_state;
 pub mod options_finder;
@@ -1,17 +1,40 @@
-use super::{machine_state::MachineStateMode, result::TabryResult};
+use super::{
+    arg_binding,
+    autocomplete_state::{self, AutocompleteState},
+    builtin_options::{self, BuiltinOption},
+    machine_state::{MachineStateMode, NamedArgValue},
+    result::TabryResult,
+};
 use crate::core::config::TabryConfError;
-use crate::core::types::{TabryConcreteArg, TabryConcreteFlag, TabryOpt};
-use std::collections::HashSet;
+use crate::core::types::{
+    TabryConcreteArg, TabryConcreteFlag, TabryFilter, TabryOpt, TabryRank, TabrySettings,
+};
+use std::collections::{HashMap, HashSet};
 use std::process::Command;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::debug;
 
-use serde_json::json;
+#[derive(Error, Debug)]
+pub enum OptionsError {
+    #[error(transparent)]
+    Config(#[from] TabryConfError),
+    /// An `opts shell` or `opts builtin` without stubbed output, when stubs are used
+    #[error("No stub output for {0:?}")]
+    MissingStub(String),
+}
 
 pub struct OptionsFinder {
     result: TabryResult,
     include_descriptions: bool,
+    frecency_scores: HashMap<String, f64>,
+    compline: Option<String>,
+    /// Output to use for `opts shell` and `opts builtin` instead of running or reading anything
+    /// (for `tabry test`); see `with_stubs`
+    stubs: Option<HashMap<String, String>>,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct OptionResult {
     pub value: String,
     pub desc: Option<String>,
@@ -19,23 +42,149 @@ pub struct OptionResult {
 
 pub struct OptionsResults {
     prefix: String,
-    pub options: HashSet<OptionResult>,
-    pub special_options: HashSet<String>,
+    filter: TabryFilter,
+    case_insensitive: bool,
+    /// Options in the order they should be offered, without duplicates
+    pub options: Vec<OptionResult>,
+    pub special_options: Vec<String>,
+    /// The `opts` used to find the options, in order (for debugging)
+    pub sources: Vec<OptionsSource>,
+    /// Values of the options and specials added so far, so checking for duplicates doesn't mean
+    /// going through them all (an `opts shell` can give tens of thousands)
+    seen_options: HashSet<String>,
+    seen_specials: HashSet<String>,
+}
+
+/// An `opts` statement used to find options, and what it gave
+#[derive(Debug)]
+pub struct OptionsSource {
+    /// The `opts` statement, e.g. `opts shell "git branch"`
+    pub source: String,
+    /// The number of options and specials it added (which matched, and weren't already added)
+    pub added: usize,
+    pub elapsed: Duration,
+}
+
+fn describe_opt(opt: &TabryOpt) -> String {
+    match opt {
+        TabryOpt::File => "opts file".to_owned(),
+        TabryOpt::Dir => "opts dir".to_owned(),
+        TabryOpt::Const { value, .. } => format!("opts const {:?}", value),
+        TabryOpt::Delegate { value } => format!("opts delegate {:?}", value),
+        TabryOpt::Command => "opts command".to_owned(),
+        TabryOpt::Shell { value, .. } => format!("opts shell {:?}", value),
+        TabryOpt::Include { value } => format!("opts @{}", value),
+        TabryOpt::Builtin { value } => format!(
+            "opts builtin {}",
+            serde_json::to_string(value).unwrap().trim_matches('"')
+        ),
+    }
+}
+
+/// True if all the characters of `pattern` appear in `value`, in order.
+fn fuzzy_matches(value: &str, pattern: &str) -> bool {
+    let mut value_chars = value.chars();
+    pattern
+        .chars()
+        .all(|pattern_char| value_chars.any(|c| c == pattern_char))
 }
 
 impl OptionsResults {
+    fn matches(&self, value: &str, ignore_case: bool) -> bool {
+        if ignore_case {
+            let value = value.to_lowercase();
+            let prefix = self.prefix.to_lowercase();
+            match self.filter {
+                TabryFilter::Prefix => value.starts_with(&prefix),
+                TabryFilter::Fuzzy => fuzzy_matches(&value, &prefix),
+            }
+        } else {
+            match self.filter {
+                TabryFilter::Prefix => value.starts_with(&self.prefix),
+                TabryFilter::Fuzzy => fuzzy_matches(value, &self.prefix),
+            }
+        }
+    }
+
     fn insert(&mut self, value: &str, desc: Option<&str>) {
-        if value.starts_with(&self.prefix) {
-            // TODO get_or_insert_owned() in nightly would be ideal
-            self.options.insert(OptionResult {
+        self.insert_with_case(value, desc, false);
+    }
+
+    /// Insert an option if it matches the token being completed. Case is ignored if
+    /// `ignore_case` is true or the `case-insensitive` setting is on. The option is always
+    /// inserted with its original case.
+    fn insert_with_case(&mut self, value: &str, desc: Option<&str>, ignore_case: bool) {
+        if self.matches(value, ignore_case || self.case_insensitive) {
+            self.insert_unfiltered(value, desc);
+        }
+    }
+
+    /// Insert an option without checking it matches the token being completed, for options
+    /// which have already been filtered (by a `prefix-aware` shell command).
+    fn insert_unfiltered(&mut self, value: &str, desc: Option<&str>) {
+        // The first occurrence of a value wins (along with its description)
+        if self.seen_options.insert(value.to_owned()) {
+            self.options.push(OptionResult {
                 value: value.to_owned(),
                 desc: desc.map(str::to_owned),
             });
         }
     }
 
+    /// For an arg or flag without any options defined: if there is nothing else to offer, add a
+    /// "description_if_optionless" special so the shell can show what is expected instead:
+    /// "description_if_optionless<TAB>name<TAB>title<TAB>desc" (any of which may be empty).
+    fn insert_hint_if_optionless(
+        &mut self,
+        name: Option<&str>,
+        title: Option<&str>,
+        desc: Option<&str>,
+    ) {
+        if !self.options.is_empty() || !self.special_options.is_empty() {
+            return;
+        }
+        if name.is_none() && title.is_none() && desc.is_none() {
+            return;
+        }
+        // specials are one per line and the fields are tab-separated
+        let field = |s: Option<&str>| s.unwrap_or("").replace(['\t', '\n'], " ");
+        self.insert_special(&format!(
+            "description_if_optionless\t{}\t{}\t{}",
+            field(name),
+            field(title),
+            field(desc)
+        ));
+    }
+
+    /// Add a "delegate_argv" special: the command line to complete with the delegate command's
+    /// own completion, as "delegate_argv<TAB>word<TAB>word...". The last word is the one being
+    /// completed (possibly empty).
+    fn insert_delegate_argv(&mut self, argv: &[String]) {
+        let mut special = "delegate_argv".to_owned();
+        for word in argv {
+            special.push('\t');
+            special.push_str(&word.replace(['\t', '\n'], " "));
+        }
+        self.insert_special(&special);
+    }
+
     fn insert_special(&mut self, value: &str) {
-        self.special_options.insert(value.to_owned());
+        if self.seen_specials.insert(value.to_owned()) {
+            self.special_options.push(value.to_owned());
+        }
+    }
+
+    fn rank(&mut self, rank: TabryRank, frecency_scores: &HashMap<String, f64>) {
+        match rank {
+            TabryRank::Config => {}
+            TabryRank::Alphabetical => self.options.sort_by(|a, b| a.value.cmp(&b.value)),
+            TabryRank::Frecency => {
+                // stable sort, so options with the same score stay in config order
+                let score =
+                    |o: &OptionResult| frecency_scores.get(&o.value).copied().unwrap_or(0.0);
+                self.options.sort_by(|a, b| score(b).total_cmp(&score(a)));
+            }
+        }
     }
 }
 
@@ -44,29 +193,83 @@ impl OptionsFinder {
         Self {
             result,
             include_descriptions,
+            frecency_scores: HashMap::new(),
+            compline: None,
+            stubs: None,
         }
     }
 
-    pub fn options(&self, token: &str) -> Result<OptionsResults, TabryConfError> {
+    /// The whole command line being completed, passed on to `opts shell` commands.
+    pub fn with_compline(mut self, compline: &str) -> Self {
+        self.compline = Some(compline.to_owned());
+        self
+    }
+
+    /// Scores (higher is better) used to order options if `set rank frecency` is in effect.
+    pub fn with_frecency_scores(mut self, frecency_scores: HashMap<String, f64>) -> Self {
+        self.frecency_scores = frecency_scores;
+        self
+    }
+
+    /// Use the given output instead of running `opts shell` commands (keyed by the command) or
+    /// finding `opts builtin` options (keyed by `builtin:<name>`, e.g. `builtin:users`, with a
+    /// line per option, optionally followed by a tab and its description). An `opts shell` or
+    /// `opts builtin` without a stub is an error.
+    pub fn with_stubs(mut self, stubs: HashMap<String, String>) -> Self {
+        self.stubs = Some(stubs);
+        self
+    }
+
+    /// The stubbed output for a key (see `with_stubs`), if stubs are used.
+    fn stub(&self, key: &str) -> Option<Result<&str, OptionsError>> {
+        let stubs = self.stubs.as_ref()?;
+        Some(
+            stubs
+                .get(key)
+                .map(String::as_str)
+                .ok_or_else(|| OptionsError::MissingStub(key.to_owned())),
+        )
+    }
+
+    pub fn options(&self, token: &str) -> Result<OptionsResults, OptionsError> {
+        let settings = TabrySettings::resolve(&self.result.sub_stack);
         let mut res = OptionsResults {
             prefix: token.to_owned(),
-            options: HashSet::new(),
-            special_options: HashSet::new(),
+            filter: settings.filter.unwrap_or_default(),
+            case_insensitive: settings.case_insensitive.unwrap_or(false),
+            options: vec![],
+            special_options: vec![],
+            sources: vec![],
+            seen_options: HashSet::new(),
+            seen_specials: HashSet::new(),
         };
 
         match self.result.state.mode {
             MachineStateMode::Subcommand => self.add_options_subcommand(&mut res)?,
             MachineStateMode::Flagarg { .. } => self.add_options_flagarg(&mut res)?,
+            MachineStateMode::Command { .. } => self.add_options_command(&mut res),
         };
 
+        res.rank(settings.rank.unwrap_or_default(), &self.frecency_scores);
+        if let Some(max_results) = settings.max_results {
+            res.options.truncate(max_results);
+        }
         Ok(res)
     }
 
-    fn add_options_subcommand(&self, res: &mut OptionsResults) -> Result<(), TabryConfError> {
+    fn add_options_subcommand(&self, res: &mut OptionsResults) -> Result<(), OptionsError> {
         // TODO: required flags
         self.add_options_subcommand_subs(res);
+        let optionless_arg = self.add_options_subcommand_args(res)?;
         self.add_options_subcommand_flags(res)?;
-        self.add_options_subcommand_args(res)?;
+
+        if let Some(arg) = optionless_arg {
+            res.insert_hint_if_optionless(
+                arg.name.as_deref(),
+                arg.title.as_deref(),
+                arg.description.as_deref(),
+            );
+        }
 
         Ok(())
     }
@@ -97,6 +300,11 @@ impl OptionsFinder {
             || self.result.state.flag_args.contains_key(&flag.name)
     }
 
+    /// Whether a flag should no longer be offered. Repeatable flags are always offered.
+    fn flag_is_used_up(&self, flag: &TabryConcreteFlag) -> bool {
+        !flag.repeatable && self.flag_is_used(flag)
+    }
+
     fn add_option_for_flag(
         res: &mut OptionsResults,
         flag: &TabryConcreteFlag,
@@ -107,17 +315,18 @@ impl OptionsFinder {
         } else {
             format!("--{}", flag.name)
         };
-        res.insert(
-            &flag_str,
-            if include_descriptions {
-                flag.description.as_deref()
-            } else {
-                None
-            },
-        );
+        let desc = if include_descriptions {
+            flag.description.as_deref()
+        } else {
+            None
+        };
+        res.insert(&flag_str, desc);
+        if flag.negatable && !flag.arg && flag.name.len() > 1 {
+            res.insert(&format!("--no-{}", flag.name), desc);
+        }
     }
 
-    fn add_options_subcommand_flags(&self, res: &mut OptionsResults) -> Result<(), TabryConfError> {
+    fn add_options_subcommand_flags(&self, res: &mut OptionsResults) -> Result<(), OptionsError> {
         if self.result.state.dashdash {
             return Ok(());
         }
@@ -132,14 +341,38 @@ impl OptionsFinder {
             return Ok(());
         }
 
+        // Flags required by other flags (`requires`) or by a `reqd exclusive` group with none of
+        // its flags given yet are treated like reqd flags.
+        let mut reqd_flag_names = self.result.unmet_flag_requirements();
+        if reqd_flag_names.is_empty() {
+            if let Some(group) = self
+                .result
+                .exclusive_flag_groups()
+                .find(|g| g.required && !g.flags.iter().any(|f| self.result.state.flag_given(f)))
+            {
+                reqd_flag_names = group.flags.iter().map(String::as_str).collect();
+            }
+        }
+        if !reqd_flag_names.is_empty() {
+            for sub in self.result.sub_stack.iter().rev() {
+                for flag in self.result.config.expand_flags(&sub.flags) {
+                    if reqd_flag_names.contains(&flag.name.as_str()) {
+                        Self::add_option_for_flag(res, flag, self.include_descriptions);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
         // Don't suggest flags unless user has typed a dash
         if !res.prefix.starts_with('-') {
             return Ok(());
         }
 
-        for sub in self.result.sub_stack.iter() {
+        // Flags of the current sub first, then those of its parents
+        for sub in self.result.sub_stack.iter().rev() {
             for flag in self.result.config.expand_flags(&sub.flags) {
-                if !self.flag_is_used(flag) {
+                if !self.flag_is_used_up(flag) && !self.result.flag_conflicts(&flag.name) {
                     Self::add_option_for_flag(res, flag, self.include_descriptions);
                 }
             }
@@ -148,82 +381,254 @@ impl OptionsFinder {
         Ok(())
     }
 
+    fn autocomplete_state<'a>(
+        &'a self,
+        prefix: &'a str,
+        arg_name: Option<&'a str>,
+    ) -> AutocompleteState<'a> {
+        let state = &self.result.state;
+        let (current_flag, arg_index) = match &state.mode {
+            MachineStateMode::Flagarg { current_flag } => (Some(current_flag.as_str()), None),
+            MachineStateMode::Subcommand | MachineStateMode::Command { .. } => {
+                (None, Some(state.args.len()))
+            }
+        };
+        AutocompleteState {
+            version: autocomplete_state::AUTOCOMPLETE_STATE_VERSION,
+            cmd: self.result.config.cmd.as_deref(),
+            subs: &state.subcommand_stack,
+            flags: &state.flags,
+            flag_args: &state.flag_args,
+            all_flags: autocomplete_state::merge_flags(&state.flags, &state.flag_args),
+            flag_counts: &state.flag_counts,
+            flag_arg_lists: &state.flag_arg_lists,
+            args: &state.args,
+            named_args: self.named_args_before_token(),
+            dashdash: state.dashdash,
+            prefix,
+            current_flag,
+            arg_index,
+            arg_name,
+            compline: self.compline.as_deref(),
+            max_results: TabrySettings::resolve(&self.result.sub_stack).max_results,
+        }
+    }
+
+    /// Add options from an arg's or flag's `opts`. `arg_name` is the name of the arg (if any),
+    /// for `opts shell` commands.
     fn add_options(
         &self,
         res: &mut OptionsResults,
         options: &Vec<TabryOpt>,
-    ) -> Result<(), TabryConfError> {
+        arg_name: Option<&str>,
+    ) -> Result<(), OptionsError> {
         for opt in options {
+            let start = Instant::now();
+            let n_before = res.options.len() + res.special_options.len();
             match &opt {
                 TabryOpt::File => res.insert_special("file"),
                 TabryOpt::Dir => res.insert_special("dir"),
-                TabryOpt::Const { value } => res.insert(value, None),
+                TabryOpt::Const {
+                    value,
+                    case_insensitive,
+                } => res.insert_with_case(value, None, *case_insensitive),
                 TabryOpt::Delegate { value } => {
-                    res.insert_special(format!("delegate {}", value).as_str())
+                    let argv = self.delegate_argv(value, &res.prefix);
+                    res.insert_delegate_argv(&argv);
                 }
-                TabryOpt::Shell { value } => {
-                    let auto_complete_state = json!({
-                        "cmd": self.result.config.cmd,
-                        "flags": self.result.state.flags,
-                        // TODO: these are merged in ruby version.
-                        "flag_args": self.result.state.flag_args,
-                        "args": self.result.state.args,
-                        // current_token. result.prefix???
-                        // "current_flag": self.result.state.current_flag,
-                        // ^ this doesn't seem to exist either for the rust version?
-                    });
-                    let output = Command::new("sh")
-                        .arg("-c")
-                        .arg(value)
-                        .env("TABRY_AUTOCOMPLETE_STATE", auto_complete_state.to_string())
-                        .output();
-                    // TODO bubble up errors instead on unwrap()
-                    let output_bytes = output.unwrap();
-                    let output_str = std::str::from_utf8(&output_bytes.stdout[..]).unwrap();
+                TabryOpt::Command => res.insert_special("command"),
+                TabryOpt::Shell {
+                    value,
+                    case_insensitive,
+                    prefix_aware,
+                } => {
+                    let output_str = match self.stub(value) {
+                        Some(stub) => stub?.to_owned(),
+                        None => self.run_shell(value, &res.prefix, arg_name),
+                    };
                     for line in output_str.split('\n') {
-                        if !line.is_empty() {
-                            res.insert(line, None);
+                        if line.is_empty() {
+                            continue;
+                        }
+                        if *prefix_aware {
+                            res.insert_unfiltered(line, None);
+                        } else {
+                            res.insert_with_case(line, None, *case_insensitive);
                         }
                     }
                 }
                 TabryOpt::Include { value } => {
-                    // TODO: what happens if there is an include loop?
-                    self.add_options(res, self.result.config.get_option_include(value)?)?;
+                    // NOTE: an include loop here will overflow the stack. Loops are caught
+                    // ahead of time when the config is linked (see TabryConf::link).
+                    self.add_options(res, self.result.config.get_option_include(value)?, arg_name)?;
+                }
+                TabryOpt::Builtin { value } => {
+                    let opts = match self.stub(&format!("builtin:{}", value.name())) {
+                        Some(stub) => stub?
+                            .lines()
+                            .filter(|line| !line.is_empty())
+                            .map(|line| match line.split_once('\t') {
+                                Some((value, desc)) => BuiltinOption::with_desc(value, desc),
+                                None => BuiltinOption::new(line),
+                            })
+                            .collect(),
+                        None => builtin_options::builtin_options(*value),
+                    };
+                    for opt in opts {
+                        let desc = opt.desc.as_deref().filter(|_| self.include_descriptions);
+                        res.insert(&opt.value, desc);
+                    }
                 }
             }
+            // The opts in an include are listed themselves
+            if !matches!(opt, TabryOpt::Include { .. }) {
+                let source = OptionsSource {
+                    source: describe_opt(opt),
+                    added: res.options.len() + res.special_options.len() - n_before,
+                    elapsed: start.elapsed(),
+                };
+                debug!(
+                    "{}: added {} in {:?}",
+                    source.source, source.added, source.elapsed
+                );
+                res.sources.push(source);
+            }
         }
         Ok(())
     }
 
-    fn add_options_subcommand_args(&self, res: &mut OptionsResults) -> Result<(), TabryConfError> {
-        let sub_args = self
-            .result
+    /// Run an `opts shell` command, returning its output.
+    fn run_shell(&self, command: &str, prefix: &str, arg_name: Option<&str>) -> String {
+        let auto_complete_state = self.autocomplete_state(prefix, arg_name);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env(
+                "TABRY_AUTOCOMPLETE_STATE",
+                serde_json::to_string(&auto_complete_state).unwrap(),
+            )
+            .envs(auto_complete_state.env_vars())
+            .output();
+        // TODO bubble up errors instead on unwrap()
+        let output_bytes = output.unwrap();
+        std::str::from_utf8(&output_bytes.stdout[..])
+            .unwrap()
+            .to_owned()
+    }
+
+    /// The command line for `opts delegate`: the delegate command's words, then the tokens
+    /// already bound to the arg being completed (for varargs, e.g. `varargs git-args` with
+    /// `opts delegate "git"`, which may include tokens after `--`), then the token being
+    /// completed. For a flag argument, only the token being completed is forwarded.
+    fn delegate_argv(&self, delegate_cmd: &str, prefix: &str) -> Vec<String> {
+        let mut argv = shell_words::split(delegate_cmd)
+            .unwrap_or_else(|_| delegate_cmd.split_whitespace().map(str::to_owned).collect());
+        if self.result.state.mode == MachineStateMode::Subcommand {
+            argv.extend(self.tokens_bound_to_current_arg());
+        }
+        argv.push(prefix.to_owned());
+        argv
+    }
+
+    /// Tokens already given for the arg the token being completed is bound to (only ever more
+    /// than none for varargs).
+    fn tokens_bound_to_current_arg(&self) -> Vec<String> {
+        let args = &self.result.state.args;
+        let bindings = arg_binding::bind_args(&self.current_sub_args(), args.len() + 1);
+        let Some(current) = bindings[args.len()] else {
+            return vec![];
+        };
+        args.iter()
+            .zip(bindings)
+            .filter(|(_, binding)| *binding == Some(current))
+            .map(|(token, _)| token.clone())
+            .collect()
+    }
+
+    fn current_sub_args(&self) -> Vec<&TabryConcreteArg> {
+        self.result
             .config
             .expand_args(&self.result.current_sub().args)
-            .collect::<Vec<_>>();
-
-        if let Some(arg) = sub_args.get(self.result.state.args.len()) {
-            self.add_options(res, &arg.options)?;
-        } else if let Some(TabryConcreteArg {
-            varargs: true,
-            options,
-            ..
-        }) = sub_args.last()
+            .collect()
+    }
+
+    /// Add options for the args the token being completed could be (more than one if there are
+    /// optional args or varargs before a required arg), returning the first arg without any
+    /// options defined, if there is one.
+    fn add_options_subcommand_args(
+        &self,
+        res: &mut OptionsResults,
+    ) -> Result<Option<&TabryConcreteArg>, OptionsError> {
+        let sub_args = self.current_sub_args();
+        let mut optionless_arg = None;
+        for i in arg_binding::possible_args_for_next_token(&sub_args, self.result.state.args.len())
         {
-            self.add_options(res, options)?;
+            let arg = sub_args[i];
+            self.add_options(res, &arg.options, arg.name.as_deref())?;
+            if arg.options.is_empty() && optionless_arg.is_none() {
+                optionless_arg = Some(arg);
+            }
         }
 
-        Ok(())
+        Ok(optionless_arg)
     }
 
-    fn add_options_flagarg(&self, res: &mut OptionsResults) -> Result<(), TabryConfError> {
+    /// Like `MachineState::named_args`, but taking into account that the token being completed
+    /// is (probably) another arg, so e.g. with `arg [opt] region; arg name`, "prod" in
+    /// "mycmd prod <TAB>" is the region, not the name.
+    fn named_args_before_token(&self) -> HashMap<String, NamedArgValue> {
+        let sub_args = self.current_sub_args();
+        let mut tokens = self.result.state.args.clone();
+        tokens.push(String::new());
+        let mut named_args = arg_binding::named_args(&sub_args, &tokens);
+        // Remove the placeholder for the token being completed
+        let last_name = arg_binding::bind_args(&sub_args, tokens.len())
+            .last()
+            .copied()
+            .flatten()
+            .and_then(|i| sub_args[i].name.as_ref());
+        if let Some(name) = last_name {
+            match named_args.get_mut(name) {
+                Some(NamedArgValue::Varargs(values)) => {
+                    values.pop();
+                }
+                _ => {
+                    named_args.remove(name);
+                }
+            }
+        }
+        named_args
+    }
+
+    /// Options for a token in another command line (`opts command`). The app completes it with
+    /// the command's own config if there is one; otherwise we get here, and delegate to the
+    /// command's own completion in the shell.
+    fn add_options_command(&self, res: &mut OptionsResults) {
+        let mut argv = self
+            .result
+            .state
+            .command_args()
+            .unwrap_or_default()
+            .to_vec();
+        argv.push(res.prefix.clone());
+        res.insert_delegate_argv(&argv);
+    }
+
+    fn add_options_flagarg(&self, res: &mut OptionsResults) -> Result<(), OptionsError> {
         let MachineStateMode::Flagarg { current_flag } = &self.result.state.mode else {
             unreachable!()
         };
         for sub in &self.result.sub_stack {
             for flag in self.result.config.expand_flags(&sub.flags) {
                 if &flag.name == current_flag {
-                    self.add_options(res, &flag.options)?;
+                    self.add_options(res, &flag.options, None)?;
+                    if flag.options.is_empty() {
+                        res.insert_hint_if_optionless(
+                            Some(&flag.name),
+                            None,
+                            flag.description.as_deref(),
+                        );
+                    }
                     return Ok(());
                 }
             }
@@ -238,7 +643,6 @@ mod tests {
     use crate::core::config::TabryConf;
     use crate::engine::machine_state::{MachineState, MachineStateMode::*};
     use crate::test_helpers::load_fixture_file;
-    use std::collections::HashMap;
     // TODO fill in from ~/dev/tabry/spec/tabry/options_finder_spec.rb
 
     fn options_with_machine_state(machine_state: MachineState, token: &str) -> OptionsResults {
@@ -271,16 +675,16 @@ mod tests {
                     ..Default::default()
                 };
                 let options_results = options_with_machine_state(machine_state, token);
-                let actual_strs : HashSet<&str> =
+                let actual_strs : Vec<&str> =
                     options_results.options.iter().map(|s| s.value.as_str()).collect();
-                let actual_specials_strs : HashSet<&str> =
+                let actual_specials_strs : Vec<&str> =
                     options_results.special_options.iter().map(|s| s.as_str()).collect();
 
-                let expected = [$($expected),*];
-                let expected_special_options = [$($($expected_special_options),*)?];
+                let expected : [&str; _] = [$($expected),*];
+                let expected_special_options : [&str; _] = [$($($expected_special_options),*)?];
 
-                assert_eq!(actual_strs, HashSet::from(expected));
-                assert_eq!(actual_specials_strs, HashSet::from(expected_special_options));
+                assert_eq!(actual_strs, expected);
+                assert_eq!(actual_specials_strs, expected_special_options);
             }
         };
     );
@@ -312,7 +716,7 @@ mod tests {
 
     test_options_finder!(
         test_possible_subcommands_of_a_subcommand,
-        ("go", "stop", "crash", "freeway-crash"),
+        ("go", "stop", "crash", "tow", "dispatch", "convoy", "freeway-crash"),
         {subcommand_stack: vec_owned!("move")}
     );
 
@@ -322,28 +726,121 @@ mod tests {
         {subcommand_stack: vec_owned!("move", "go")}
     );
 
+    test_options_finder!(
+        test_lists_options_for_an_optional_arg_and_the_required_arg_after_it,
+        ("depot", "street", "car", "bike"),
+        {subcommand_stack: vec_owned!("move", "tow")}
+    );
+
+    test_options_finder!(
+        test_lists_options_for_the_required_arg_after_an_optional_arg_has_been_given,
+        ("car", "bike"),
+        {
+            subcommand_stack: vec_owned!("move", "tow"),
+            args: vec_owned!("depot")
+        }
+    );
+
     test_options_finder!(
         test_lists_options_for_varargs,
         ("car", "bike"),
         {subcommand_stack: vec_owned!("build")}
     );
 
+    test_options_finder!(
+        test_filters_options_fuzzily_if_filter_is_fuzzy,
+        ("freeway-crash"),
+        {subcommand_stack: vec_owned!("move")},
+        "fwc"
+    );
+
+    test_options_finder!(
+        test_lists_options_in_config_order_by_default,
+        ("Table", "CSV"),
+        {
+            subcommand_stack: vec_owned!("list-vehicles"),
+            mode: Flagarg { current_flag: "format".to_owned() }
+        }
+    );
+
+    #[test]
+    fn test_named_args_for_shell_opts_count_the_token_being_completed() {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        let machine_state = MachineState {
+            subcommand_stack: vec_owned!("move", "tow"),
+            args: vec_owned!("depot"),
+            ..Default::default()
+        };
+        let options_finder = OptionsFinder::new(TabryResult::new(tabry_conf, machine_state), false);
+        assert_eq!(
+            options_finder.named_args_before_token(),
+            HashMap::from([("from".to_owned(), NamedArgValue::Single("depot".to_owned()))])
+        );
+    }
+
+    #[test]
+    fn test_ranks_options_by_frecency_scores_if_rank_is_frecency() {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        let machine_state = MachineState {
+            subcommand_stack: vec_owned!("move", "go"),
+            ..Default::default()
+        };
+        let options_finder = OptionsFinder::new(TabryResult::new(tabry_conf, machine_state), false)
+            .with_frecency_scores(HashMap::from([("bike".to_owned(), 2.0)]));
+        let options_results = options_finder.options("").unwrap();
+        let actual_strs: Vec<&str> = options_results
+            .options
+            .iter()
+            .map(|s| s.value.as_str())
+            .collect();
+        assert_eq!(actual_strs, ["bike", "car"]);
+    }
+
+    test_options_finder!(test_filters_options_by_prefix_by_default, (), {}, "lv");
+
+    test_options_finder!(
+        test_ignores_case_when_filtering_if_case_insensitive_is_set,
+        ("crash", "freeway-crash"),
+        {subcommand_stack: vec_owned!("move")},
+        "CR"
+    );
+
+    test_options_finder!(
+        test_ignores_case_of_case_insensitive_opts,
+        ("Table"),
+        {
+            subcommand_stack: vec_owned!("list-vehicles"),
+            mode: Flagarg { current_flag: "format".to_owned() }
+        },
+        "t"
+    );
+
+    test_options_finder!(
+        test_keeps_case_of_other_opts,
+        (),
+        {
+            subcommand_stack: vec_owned!("list-vehicles"),
+            mode: Flagarg { current_flag: "format".to_owned() }
+        },
+        "c"
+    );
+
     test_options_finder!(
         test_lists_both_possible_args_and_subcommand_stack_if_a_subcommand_can_take_either,
-        ("x","y","z","subsub"),
+        ("subsub","x","y","z"),
         {subcommand_stack: vec_owned!("sub-with-sub-or-arg")}
     );
 
     test_options_finder!(
         test_lists_possible_flags_if_the_last_token_starts_with_a_dash,
-        ("--verbose", "--speed", "--output-to-file", "--output-to-directory", "--dry-run"),
+        ("--speed", "--dry-run", "--output-to-file", "--output-to-directory", "--verbose"),
         {subcommand_stack: vec_owned!("move", "crash")},
         "-"
     );
 
     test_options_finder!(
         test_doesn_t_list_a_flag_if_it_has_already_been_given,
-        ("--verbose","--speed","--output-to-file","--output-to-directory"),
+        ("--speed","--output-to-file","--output-to-directory","--verbose"),
         {
           flags: hashmap_owned!("dry-run" => true),
           subcommand_stack: vec_owned!("move", "crash")
@@ -351,6 +848,61 @@ mod tests {
         "-"
     );
 
+    test_options_finder!(
+        test_keeps_listing_repeatable_flags_after_they_have_been_given,
+        (
+            "--detailed", "--tag", "--json", "--color", "--no-color", "--yaml", "--output-file",
+            "--format", "--verbose"
+        ),
+        {
+          flags: hashmap_owned!("detailed" => true),
+          flag_args: hashmap_owned!("tag" => "red"),
+          subcommand_stack: vec_owned!("list-vehicles")
+        },
+        "-"
+    );
+
+    test_options_finder!(
+        test_doesnt_list_flags_exclusive_with_a_flag_already_given,
+        ("--detailed", "--tag", "--color", "--no-color", "--output-file", "--format", "--verbose"),
+        {
+          flags: hashmap_owned!("json" => true),
+          subcommand_stack: vec_owned!("list-vehicles")
+        },
+        "-"
+    );
+
+    test_options_finder!(
+        test_doesnt_list_either_form_of_a_negatable_flag_once_one_is_given,
+        ("--detailed", "--tag", "--json", "--yaml", "--output-file", "--format", "--verbose"),
+        {
+          flags: hashmap_owned!("color" => false),
+          subcommand_stack: vec_owned!("list-vehicles")
+        },
+        "-"
+    );
+
+    test_options_finder!(
+        test_lists_only_flags_required_by_a_flag_already_given,
+        ("--format"),
+        {
+          flag_args: hashmap_owned!("output-file" => "foo.txt"),
+          subcommand_stack: vec_owned!("list-vehicles")
+        }
+    );
+
+    #[test]
+    fn test_lists_flags_of_a_required_exclusive_group_of_a_parent_sub() {
+        let config = crate::lang::compile(
+            "reqd exclusive (id name)\nflag id\nflag name\nsub show { flag verbose }",
+        )
+        .unwrap();
+        let result = crate::engine::machine::Machine::run(config, &["show".to_owned()]).unwrap();
+        let options = OptionsFinder::new(result, false).options("").unwrap();
+        let values: Vec<&str> = options.options.iter().map(|o| o.value.as_str()).collect();
+        assert_eq!(values, ["--id", "--name"]);
+    }
+
     test_options_finder!(
         test_doesnt_suggests_flags_if_double_dash_has_been_used,
         (),
@@ -368,6 +920,7 @@ mod tests {
     //     {subcommand_stack: vec_owned!("sub-with-mandatory-flag")}
     // );
 
+    // sub-with-mandatory-flag has "set rank alphabetical" and its options are defined as c, a, b
     test_options_finder!(
         test_lists_other_args_after_a_mandatory_flag_has_been_given,
         ("a","b","c"),
@@ -386,6 +939,69 @@ mod tests {
         }
     );
 
+    test_options_finder!(
+        test_passes_autocomplete_state_to_shell_opts,
+        ("move-tow", "\"current_flag\":\"to\""),
+        {
+            subcommand_stack: vec_owned!("move", "tow"),
+            mode: Flagarg { current_flag: "to".to_owned() }
+        }
+    );
+
+    test_options_finder!(
+        test_doesnt_filter_output_of_prefix_aware_shell_opts,
+        ("lot-x", "other"),
+        {
+            subcommand_stack: vec_owned!("move", "tow"),
+            mode: Flagarg { current_flag: "lot".to_owned() }
+        },
+        "x"
+    );
+
+    test_options_finder!(
+        test_delegates_with_the_delegate_command_and_the_token_being_completed,
+        (; "delegate_argv\tradio\t--channel\t9\tco"),
+        {subcommand_stack: vec_owned!("move", "dispatch")},
+        "co"
+    );
+
+    test_options_finder!(
+        test_delegates_with_the_tokens_already_given_for_varargs,
+        (; "delegate_argv\tradio\t--channel\t9\tsay\t--loud\t"),
+        {
+            subcommand_stack: vec_owned!("move", "dispatch"),
+            args: vec_owned!("say", "--loud"),
+            dashdash: true
+        }
+    );
+
+    test_options_finder!(
+        test_offers_commands_for_an_opts_command_arg,
+        (; "command"),
+        {
+            subcommand_stack: vec_owned!("move", "convoy"),
+            args: vec_owned!("car")
+        },
+        "veh"
+    );
+
+    test_options_finder!(
+        test_delegates_the_rest_of_the_command_line_in_command_mode,
+        (; "delegate_argv\tls\t-l\t/tm"),
+        {
+            subcommand_stack: vec_owned!("move", "convoy"),
+            args: vec_owned!("car", "ls", "-l"),
+            mode: MachineStateMode::Command { first_arg: 1 }
+        },
+        "/tm"
+    );
+
+    test_options_finder!(
+        test_limits_number_of_options_if_max_results_is_set,
+        ("car"),
+        {subcommand_stack: vec_owned!("move", "freeway-crash")}
+    );
+
     test_options_finder!(
         test_lists_possibilities_for_a_flag_arguments_file_const,
         ("-"; "file"), // special "file" option
@@ -404,6 +1020,24 @@ mod tests {
         }
     );
 
+    test_options_finder!(
+        test_gives_a_hint_for_an_arg_without_options,
+        (; "description_if_optionless\treason\treason for stopping\tFree-form text, e.g. 'red light'"),
+        {
+            subcommand_stack: vec_owned!("move", "stop"),
+            args: vec_owned!("car")
+        }
+    );
+
+    test_options_finder!(
+        test_gives_a_hint_for_a_flag_argument_without_options,
+        (; "description_if_optionless\ttag\t\tOnly list vehicles with this tag"),
+        {
+            subcommand_stack: vec_owned!("list-vehicles"),
+            mode: Flagarg { current_flag: "tag".to_owned() }
+        }
+    );
+
     test_options_finder!(
         test_lists_nothing_if_no_options_are_defined,
         (),
@@ -1,5 +1,8 @@
 use super::machine_state::MachineState;
-use crate::core::{config::TabryConf, types::TabryConcreteSub};
+use crate::core::{
+    config::TabryConf,
+    types::{TabryConcreteSub, TabryExclusiveFlags, TabryFlagRequirement},
+};
 
 /// Encapsulates a TabryConfig and a TabryMachineState state, and provides
 /// functionality relating to this state.
@@ -31,4 +34,131 @@ impl TabryResult {
     pub fn current_sub(&self) -> &TabryConcreteSub {
         self.sub_stack.last().unwrap()
     }
+
+    /// All exclusive flag groups that apply (from the current sub and its ancestors).
+    pub fn exclusive_flag_groups(&self) -> impl Iterator<Item = &TabryExclusiveFlags> {
+        self.sub_stack.iter().flat_map(|sub| &sub.exclusive_flags)
+    }
+
+    /// All flag requirements that apply (from the current sub and its ancestors).
+    pub fn flag_requirements(&self) -> impl Iterator<Item = &TabryFlagRequirement> {
+        self.sub_stack.iter().flat_map(|sub| &sub.flag_requirements)
+    }
+
+    /// Whether giving this flag would conflict with a flag that has already been given.
+    pub fn flag_conflicts(&self, name: &str) -> bool {
+        self.exclusive_flag_groups().any(|group| {
+            group.flags.iter().any(|f| f == name)
+                && group
+                    .flags
+                    .iter()
+                    .any(|other| other != name && self.state.flag_given(other))
+        })
+    }
+
+    /// Flags that must still be given because a flag that requires them has been given.
+    pub fn unmet_flag_requirements(&self) -> Vec<&str> {
+        let mut result: Vec<&str> = vec![];
+        for req in self.flag_requirements() {
+            if !self.state.flag_given(&req.flag) {
+                continue;
+            }
+            for required in &req.requires {
+                if !self.state.flag_given(required) && !result.contains(&required.as_str()) {
+                    result.push(required);
+                }
+            }
+        }
+        result
+    }
+
+    /// Problems with the flags given, for validating a complete command line: missing reqd
+    /// flags, and violated `exclusive` and `requires` constraints.
+    pub fn flag_violations(&self) -> Vec<String> {
+        let mut violations = vec![];
+
+        for sub in &self.sub_stack {
+            for flag in self.config.expand_flags(&sub.flags) {
+                if flag.required && !self.state.flag_given(&flag.name) {
+                    violations.push(format!("missing required flag --{}", flag.name));
+                }
+            }
+        }
+
+        for group in self.exclusive_flag_groups() {
+            let given = group
+                .flags
+                .iter()
+                .filter(|f| self.state.flag_given(f))
+                .map(|f| format!("--{}", f))
+                .collect::<Vec<_>>();
+            let all = group
+                .flags
+                .iter()
+                .map(|f| format!("--{}", f))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if given.len() > 1 {
+                violations.push(format!("flags {} can't be used together", given.join(", ")));
+            } else if given.is_empty() && group.required {
+                violations.push(format!("one of the flags {} is required", all));
+            }
+        }
+
+        for req in self.flag_requirements() {
+            if !self.state.flag_given(&req.flag) {
+                continue;
+            }
+            for required in &req.requires {
+                if !self.state.flag_given(required) {
+                    violations.push(format!("flag --{} requires --{}", req.flag, required));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::config::TabryConf;
+    use crate::engine::machine::Machine;
+    use crate::test_helpers::load_fixture_file;
+
+    fn violations(tokens: &[&str]) -> Vec<String> {
+        let tabry_conf: TabryConf = load_fixture_file("vehicles.json");
+        let tokens = tokens.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Machine::run(tabry_conf, &tokens).unwrap().flag_violations()
+    }
+
+    #[test]
+    fn test_flag_violations_none() {
+        assert!(violations(&["list-vehicles", "--json"]).is_empty());
+        assert!(violations(&["list-vehicles", "--output-file", "x", "--format", "y"]).is_empty());
+    }
+
+    #[test]
+    fn test_flag_violations_exclusive() {
+        assert_eq!(
+            violations(&["list-vehicles", "--yaml", "--json"]),
+            vec!["flags --json, --yaml can't be used together"]
+        );
+    }
+
+    #[test]
+    fn test_flag_violations_requires() {
+        assert_eq!(
+            violations(&["list-vehicles", "--output-file", "x"]),
+            vec!["flag --output-file requires --format"]
+        );
+    }
+
+    #[test]
+    fn test_flag_violations_reqd_flag() {
+        assert_eq!(
+            violations(&["sub-with-mandatory-flag"]),
+            vec!["missing required flag --mandatory"]
+        );
+    }
 }
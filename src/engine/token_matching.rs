@@ -4,21 +4,66 @@
 use crate::core::types::*;
 
 pub trait TokenMatching {
-    fn match_token(&self, token: &str) -> bool;
+    fn match_token(&self, token: &str) -> bool {
+        self.match_token_with_case(token, false)
+    }
+
+    /// Matches the "--no-<name>" form of a negatable flag.
+    fn match_negated_token(&self, token: &str) -> bool {
+        self.match_negated_token_with_case(token, false)
+    }
+
+    fn match_token_with_case(&self, token: &str, ignore_case: bool) -> bool;
+    fn match_negated_token_with_case(&self, token: &str, ignore_case: bool) -> bool;
+}
+
+fn names_equal(name: &str, s: &str, ignore_case: bool) -> bool {
+    if ignore_case {
+        name.to_lowercase() == s.to_lowercase()
+    } else {
+        name == s
+    }
 }
 
-fn alias_matches(alias: &str, token: &str) -> bool {
+// Case is never ignored for short (one-character) flags, since "-v" and "-V" are usually
+// different flags even in otherwise case-insensitive CLIs.
+fn alias_matches(alias: &str, token: &str, ignore_case: bool) -> bool {
     if alias.len() > 1 {
-        token.len() >= 3 && &token[0..=1] == "--" && &token[2..] == alias
+        token.len() >= 3 && &token[0..=1] == "--" && names_equal(alias, &token[2..], ignore_case)
     } else {
         token.len() >= 2 && &token[0..=0] == "-" && &token[1..] == alias
     }
 }
 
+// Only long names can be negated ("--no-f" for "-f" would be confusing)
+fn negated_alias_matches(alias: &str, token: &str, ignore_case: bool) -> bool {
+    match (token.get(0..5), token.get(5..)) {
+        (Some(no), Some(rest)) => {
+            alias.len() > 1
+                && names_equal(no, "--no-", ignore_case)
+                && names_equal(alias, rest, ignore_case)
+        }
+        _ => false,
+    }
+}
+
 impl TokenMatching for TabryConcreteFlag {
-    fn match_token(&self, token: &str) -> bool {
-        alias_matches(&self.name, token)
-            || self.aliases.iter().any(|alias| alias_matches(alias, token))
+    fn match_token_with_case(&self, token: &str, ignore_case: bool) -> bool {
+        alias_matches(&self.name, token, ignore_case)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias_matches(alias, token, ignore_case))
+    }
+
+    fn match_negated_token_with_case(&self, token: &str, ignore_case: bool) -> bool {
+        self.negatable
+            && !self.arg
+            && (negated_alias_matches(&self.name, token, ignore_case)
+                || self
+                    .aliases
+                    .iter()
+                    .any(|alias| negated_alias_matches(alias, token, ignore_case)))
     }
 }
 
@@ -37,6 +82,8 @@ mod tests {
             options: vec![],
             arg: false,
             required: false,
+            repeatable: false,
+            negatable: false,
             description: Some("foo".into()),
         }
     }
@@ -77,4 +124,35 @@ mod tests {
         assert!(!flag.match_token("-"));
         assert!(!flag.match_token("-ba"));
     }
+
+    #[test]
+    fn test_match_negated_token() {
+        let mut flag = make_concrete_flag();
+        assert!(!flag.match_negated_token("--no-foo"));
+
+        flag.negatable = true;
+        assert!(flag.match_negated_token("--no-foo"));
+        assert!(flag.match_negated_token("--no-foobar"));
+        assert!(!flag.match_token("--no-foo"));
+        assert!(!flag.match_negated_token("--foo"));
+        assert!(!flag.match_negated_token("--no-f"));
+        assert!(!flag.match_negated_token("-no-foo"));
+        assert!(!flag.match_negated_token("--no-"));
+
+        flag.arg = true;
+        assert!(!flag.match_negated_token("--no-foo"));
+    }
+
+    #[test]
+    fn test_match_token_with_case() {
+        let mut flag = make_concrete_flag();
+        flag.negatable = true;
+        assert!(!flag.match_token("--FOO"));
+        assert!(flag.match_token_with_case("--FOO", true));
+        assert!(flag.match_token_with_case("--FooBar", true));
+        assert!(flag.match_negated_token_with_case("--NO-FOO", true));
+        assert!(!flag.match_negated_token_with_case("--NO-FOO", false));
+        // short flags are always case-sensitive
+        assert!(!flag.match_token_with_case("-F", true));
+    }
 }
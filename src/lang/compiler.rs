@@ -8,7 +8,6 @@ use thiserror::Error;
 use super::parser;
 use crate::core::config;
 use crate::core::types;
-use crate::core::util::is_debug;
 
 #[inline(always)]
 fn make_new_sub() -> types::TabryConcreteSub {
@@ -21,6 +20,19 @@ fn make_new_sub() -> types::TabryConcreteSub {
         flags: vec![],
         aliases: vec![],
         description: None,
+        exclusive_flags: vec![],
+        flag_requirements: vec![],
+        settings: Default::default(),
+    }
+}
+
+fn apply_setting(settings: &mut types::TabrySettings, stmt: parser::SetStatement) {
+    match stmt {
+        parser::SetStatement::SubMatching(value) => settings.sub_matching = Some(value),
+        parser::SetStatement::Filter(value) => settings.filter = Some(value),
+        parser::SetStatement::CaseInsensitive(value) => settings.case_insensitive = Some(value),
+        parser::SetStatement::Rank(value) => settings.rank = Some(value),
+        parser::SetStatement::MaxResults(value) => settings.max_results = Some(value),
     }
 }
 
@@ -51,13 +63,23 @@ fn add_opts(opts: &mut Vec<types::TabryOpt>, stmt: parser::OptsStatement) {
     match stmt {
         parser::OptsStatement::File => opts.push(types::TabryOpt::File),
         parser::OptsStatement::Dir => opts.push(types::TabryOpt::Dir),
-        parser::OptsStatement::Const { values } => {
+        parser::OptsStatement::Command => opts.push(types::TabryOpt::Command),
+        parser::OptsStatement::Const { values, modifiers } => {
+            let case_insensitive = modifiers.contains(&parser::OptsModifier::CaseInsensitive);
             for value in values {
-                opts.push(types::TabryOpt::Const { value })
+                opts.push(types::TabryOpt::Const {
+                    value,
+                    case_insensitive,
+                })
             }
         }
-        parser::OptsStatement::Shell { value } => opts.push(types::TabryOpt::Shell { value }),
+        parser::OptsStatement::Shell { value, modifiers } => opts.push(types::TabryOpt::Shell {
+            value,
+            case_insensitive: modifiers.contains(&parser::OptsModifier::CaseInsensitive),
+            prefix_aware: modifiers.contains(&parser::OptsModifier::PrefixAware),
+        }),
         parser::OptsStatement::Delegate { value } => opts.push(types::TabryOpt::Delegate { value }),
+        parser::OptsStatement::Builtin { value } => opts.push(types::TabryOpt::Builtin { value }),
     }
 }
 
@@ -76,6 +98,8 @@ fn add_flags_from_flag_statement(flags: &mut Vec<types::TabryFlag>, stmt: parser
             options: vec![],
             arg: stmt.has_arg,
             required: stmt.required,
+            repeatable: stmt.repeatable,
+            negatable: stmt.negatable,
         };
         // TODO: again, potential optimizations in this function -- don't clone if only one
         add_include_opts(&mut flag.options, stmt.includes.clone());
@@ -102,6 +126,7 @@ fn add_flags_from_flag_statement(flags: &mut Vec<types::TabryFlag>, stmt: parser
 fn make_arg(stmt: &parser::ArgStatement, name: Option<String>) -> types::TabryArg {
     let mut arg = types::TabryConcreteArg {
         name,
+        title: None,
         description: stmt.description.clone(),
         varargs: stmt.varargs,
         optional: stmt.optional,
@@ -115,11 +140,11 @@ fn make_arg(stmt: &parser::ArgStatement, name: Option<String>) -> types::TabryAr
                 add_include_opts(&mut arg.options, include_stmt.includes)
             }
             parser::Statement::Title(title_stmt) => {
-                // TODO (not supported in types module yet)
-                // for now this avoids the 'unused' warning for Title.title
-                if is_debug() {
-                    eprintln!("ignoring title: {:?}", title_stmt.title);
+                if arg.title.is_some() {
+                    // TODO errors for real, dedup with cmd
+                    panic!("multiple title statements found");
                 }
+                arg.title = Some(title_stmt.title);
             }
             parser::Statement::Desc(desc_stmt) => {
                 if arg.description.is_some() {
@@ -186,6 +211,19 @@ fn process_statement_inside_sub_or_defargs(
 fn process_statement_inside_sub(sub: &mut types::TabryConcreteSub, statement: parser::Statement) {
     match statement {
         parser::Statement::Desc(desc) => sub.description = Some(desc.desc),
+        parser::Statement::Exclusive(exclusive) => {
+            sub.exclusive_flags.push(types::TabryExclusiveFlags {
+                flags: exclusive.flags,
+                required: exclusive.required,
+            })
+        }
+        parser::Statement::Requires(requires) => {
+            sub.flag_requirements.push(types::TabryFlagRequirement {
+                flag: requires.flag,
+                requires: requires.requires,
+            })
+        }
+        parser::Statement::Set(set) => apply_setting(&mut sub.settings, set),
         _ => process_statement_inside_sub_or_defargs(
             &mut sub.subs,
             &mut sub.args,
@@ -237,6 +275,8 @@ pub fn compile(tabry_file: parser::TabryFile) -> Result<config::TabryConf, Compi
     let mut conf = config::TabryConf {
         main: make_new_sub(),
         cmd: None,
+        cmd_aliases: vec![],
+        extends: false,
         arg_includes: HashMap::new(),
         option_includes: HashMap::new(),
     };
@@ -259,7 +299,9 @@ pub fn compile(tabry_file: parser::TabryFile) -> Result<config::TabryConf, Compi
                     // TODO errors for real
                 }
                 conf.cmd = Some(cmd.name);
+                conf.cmd_aliases = cmd.aliases;
             }
+            parser::Statement::Extends => conf.extends = true,
             _ => process_statement_inside_sub(&mut conf.main, statement),
         }
     }
@@ -49,4 +49,14 @@ mod tests {
             assert_json_eq!(res, expected);
         }
     }
+
+    #[test]
+    fn test_prefix_aware_is_only_allowed_for_shell_opts() {
+        assert!(compile("arg { opts shell \"ls\" { prefix-aware case-insensitive } }").is_ok());
+        assert!(compile("arg { opts const (a b) { case-insensitive } }").is_ok());
+        assert!(matches!(
+            compile("arg { opts const (a b) { prefix-aware } }"),
+            Err(LangError::ParseError(_))
+        ));
+    }
 }
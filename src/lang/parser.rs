@@ -9,6 +9,7 @@ use winnow::{
 };
 
 use super::lexer::Token;
+use crate::core::types::{TabryBuiltin, TabryFilter, TabryRank, TabrySubMatching};
 // TODO errors are still hard to figure out, doesn't seem like the context() calls are doing much
 
 // In this parse tree, anything that comes from Lexer as &'a str, we could avoid copying into a
@@ -99,14 +100,15 @@ pub struct TabryFile {
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct CmdStatement {
     pub name: String,
+    pub aliases: Vec<String>,
 }
 
+// cmd kubectl
+// cmd kubectl,k -- the command is also run as "k"
 fn parse_cmd_statement(i: &mut &[Token]) -> PResult<CmdStatement> {
-    let mut parser = preceded(Token::Identifier("cmd"), parse_identifier);
-    let name = parser.parse_next(i)?;
-    Ok(CmdStatement {
-        name: name.to_string(),
-    })
+    let mut parser = preceded(Token::Identifier("cmd"), parse_identifier_and_aliases);
+    let NameAndAliases { name, aliases } = parser.parse_next(i)?;
+    Ok(CmdStatement { name, aliases })
 }
 
 #[derive(Clone, Debug, PartialEq, Default)]
@@ -128,10 +130,7 @@ pub struct TitleStatement {
 fn parse_title_statement(i: &mut &[Token]) -> PResult<TitleStatement> {
     let mut parser = preceded(
         Token::Identifier("title"),
-        alt((
-            parse_string_literal,
-            parse_identifier.map(|s| s.to_owned())
-        ))
+        alt((parse_string_literal, parse_identifier.map(|s| s.to_owned()))),
     );
 
     let title = parser.parse_next(i)?;
@@ -158,9 +157,65 @@ fn parse_include_statement(i: &mut &[Token]) -> PResult<IncludeStatement> {
 pub enum OptsStatement {
     File,
     Dir,
-    Const { values: Vec<String> },
-    Shell { value: String },
-    Delegate { value: String },
+    Command,
+    Const {
+        values: Vec<String>,
+        modifiers: Vec<OptsModifier>,
+    },
+    Shell {
+        value: String,
+        modifiers: Vec<OptsModifier>,
+    },
+    Delegate {
+        value: String,
+    },
+    Builtin {
+        value: TabryBuiltin,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptsModifier {
+    CaseInsensitive,
+    /// Only allowed for "opts shell"
+    PrefixAware,
+}
+
+// Optional block of modifiers after "opts const"/"opts shell", each matched by `modifier`
+fn parse_opts_modifiers(
+    i: &mut &[Token],
+    modifier: fn(&mut &[Token]) -> PResult<OptsModifier>,
+) -> PResult<Vec<OptsModifier>> {
+    opt(delimited(
+        Token::OpenBrace,
+        repeat(0.., modifier),
+        Token::CloseBrace,
+    ))
+    .context(StrContext::Label("opts modifiers"))
+    .map(Option::unwrap_or_default)
+    .parse_next(i)
+}
+
+fn parse_case_insensitive_modifier(i: &mut &[Token]) -> PResult<OptsModifier> {
+    Token::Identifier("case-insensitive")
+        .value(OptsModifier::CaseInsensitive)
+        .parse_next(i)
+}
+
+// opts const (Foo Bar) { case-insensitive }
+fn parse_const_opts_modifiers(i: &mut &[Token]) -> PResult<Vec<OptsModifier>> {
+    parse_opts_modifiers(i, parse_case_insensitive_modifier)
+}
+
+// opts shell "list-things $TABRY_PREFIX" { prefix-aware case-insensitive }
+fn parse_shell_opts_modifiers(i: &mut &[Token]) -> PResult<Vec<OptsModifier>> {
+    parse_opts_modifiers(i, |i| {
+        alt((
+            parse_case_insensitive_modifier,
+            Token::Identifier("prefix-aware").value(OptsModifier::PrefixAware),
+        ))
+        .parse_next(i)
+    })
 }
 
 // TODO: optimization would be to do an Either<Vec<String>, Vec<Vec<String>> since most of the
@@ -225,18 +280,27 @@ fn parse_opts_statement(i: &mut &[Token]) -> PResult<OptsStatement> {
         alt((
             Token::Identifier("file").map(|_| OptsStatement::File),
             Token::Identifier("dir").map(|_| OptsStatement::Dir),
+            Token::Identifier("command").map(|_| OptsStatement::Command),
             seq!(OptsStatement::Const {
                 _: Token::Identifier("const"),
-                values: parse_opts_id_string_or_list
+                values: parse_opts_id_string_or_list,
+                modifiers: parse_const_opts_modifiers
             }),
             seq!(OptsStatement::Shell {
                 _: Token::Identifier("shell"),
-                value: parse_string_literal
+                value: parse_string_literal,
+                modifiers: parse_shell_opts_modifiers
             }),
             seq!(OptsStatement::Delegate {
                 _: Token::Identifier("delegate"),
                 value: parse_string_literal
             }),
+            seq!(OptsStatement::Builtin {
+                _: Token::Identifier("builtin"),
+                value: parse_identifier
+                    .verify_map(TabryBuiltin::from_name)
+                    .context(StrContext::Label("builtin options name"))
+            }),
         ))
         .context(StrContext::Expected(StrContextValue::Description(
             "opts type (file, dir, const, etc.) and value if appropriate",
@@ -245,6 +309,121 @@ fn parse_opts_statement(i: &mut &[Token]) -> PResult<OptsStatement> {
     .parse_next(i)
 }
 
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ExclusiveStatement {
+    pub required: bool,
+    pub flags: Vec<String>,
+}
+
+// exclusive (json yaml) -- at most one of these flags
+// reqd exclusive (id name) -- exactly one of these flags
+fn parse_exclusive_statement(i: &mut &[Token]) -> PResult<ExclusiveStatement> {
+    let (required, flags): (bool, Vec<&str>) = seq!(
+        opt(Token::Identifier("reqd")).map(|t| t.is_some()),
+        _: Token::Identifier("exclusive"),
+        delimited(
+            Token::OpenParen,
+            repeat(2.., parse_identifier),
+            Token::CloseParen,
+        )
+        .context(StrContext::Expected(StrContextValue::Description(
+            "(list of at least two flag names)",
+        )))
+    )
+    .parse_next(i)?;
+    Ok(ExclusiveStatement {
+        required,
+        flags: flags.iter().map(|s| s.to_string()).collect(),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RequiresStatement {
+    pub flag: String,
+    pub requires: Vec<String>,
+}
+
+// requires output-to-file format -- if --output-to-file is given, --format must be too
+// requires output-to-file (format name)
+fn parse_requires_statement(i: &mut &[Token]) -> PResult<RequiresStatement> {
+    let (flag, requires) = seq!(
+        _: Token::Identifier("requires"),
+        parse_identifier.context(StrContext::Label("requires flag name")),
+        parse_identifier_or_list.context(StrContext::Label("required flag names"))
+    )
+    .parse_next(i)?;
+    Ok(RequiresStatement {
+        flag: flag.to_string(),
+        requires: requires.iter().map(|s| s.to_string()).collect(),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SetStatement {
+    SubMatching(TabrySubMatching),
+    Filter(TabryFilter),
+    CaseInsensitive(bool),
+    Rank(TabryRank),
+    MaxResults(usize),
+}
+
+// "true" or "false". Used for boolean settings, where the value is optional and defaults to true.
+fn parse_optional_bool(i: &mut &[Token]) -> PResult<bool> {
+    opt(alt((
+        Token::Identifier("true").value(true),
+        Token::Identifier("false").value(false),
+    )))
+    .map(|b| b.unwrap_or(true))
+    .parse_next(i)
+}
+
+// set sub-matching prefix
+// set filter fuzzy
+// set case-insensitive (or: set case-insensitive false)
+fn parse_set_statement(i: &mut &[Token]) -> PResult<SetStatement> {
+    preceded(
+        Token::Identifier("set"),
+        alt((
+            preceded(
+                Token::Identifier("sub-matching"),
+                alt((
+                    Token::Identifier("exact").value(TabrySubMatching::Exact),
+                    Token::Identifier("prefix").value(TabrySubMatching::Prefix),
+                )),
+            )
+            .map(SetStatement::SubMatching),
+            preceded(
+                Token::Identifier("filter"),
+                alt((
+                    Token::Identifier("prefix").value(TabryFilter::Prefix),
+                    Token::Identifier("fuzzy").value(TabryFilter::Fuzzy),
+                )),
+            )
+            .map(SetStatement::Filter),
+            preceded(Token::Identifier("case-insensitive"), parse_optional_bool)
+                .map(SetStatement::CaseInsensitive),
+            preceded(
+                Token::Identifier("rank"),
+                alt((
+                    Token::Identifier("config").value(TabryRank::Config),
+                    Token::Identifier("alphabetical").value(TabryRank::Alphabetical),
+                    Token::Identifier("frecency").value(TabryRank::Frecency),
+                )),
+            )
+            .map(SetStatement::Rank),
+            preceded(
+                Token::Identifier("max-results"),
+                parse_identifier.try_map(str::parse::<usize>),
+            )
+            .map(SetStatement::MaxResults),
+        ))
+        .context(StrContext::Expected(StrContextValue::Description(
+            "setting name and value (e.g. sub-matching prefix, filter fuzzy)",
+        ))),
+    )
+    .parse_next(i)
+}
+
 // ============ SUB, FLAG, ARG, DEFARGS, DEFOPTS STATEMENTS (CAN TAKE A BLOCK) ============
 
 #[derive(Clone, Debug, PartialEq, Default)]
@@ -380,15 +559,30 @@ fn parse_arg_statement(i: &mut &[Token]) -> PResult<ArgStatement> {
 pub struct FlagStatement {
     pub names_and_aliases: Vec<NameAndAliases>,
     pub required: bool,
+    pub repeatable: bool,
+    pub negatable: bool,
     pub has_arg: bool,
     pub description: Option<String>,
     pub includes: Vec<String>,
     pub statements: Vec<Statement>,
 }
 
+// Modifiers that can come before "flag"/"flagarg", in any order: e.g. "reqd repeatable flagarg"
+fn parse_flag_modifiers<'a>(i: &mut &[Token<'a>]) -> PResult<Vec<Token<'a>>> {
+    repeat(
+        0..,
+        alt((
+            Token::Identifier("reqd"),
+            Token::Identifier("repeatable"),
+            Token::Identifier("negatable"),
+        )),
+    )
+    .parse_next(i)
+}
+
 fn parse_flag_statement(i: &mut &[Token]) -> PResult<FlagStatement> {
-    let (required, has_arg, names_and_aliases, description, includes, statements_in_block) = seq!(
-        opt(Token::Identifier("reqd")).map(|t| t.is_some()),
+    let (modifiers, has_arg, names_and_aliases, description, includes, statements_in_block) = seq!(
+        parse_flag_modifiers,
         alt((Token::Identifier("flag"), Token::Identifier("flagarg")))
             .map(|t| t.is_identifier("flagarg")),
         parse_identifier_and_aliases_or_list.context(StrContext::Label(
@@ -414,7 +608,9 @@ fn parse_flag_statement(i: &mut &[Token]) -> PResult<FlagStatement> {
         has_arg,
         includes,
         description,
-        required,
+        required: modifiers.iter().any(|t| t.is_identifier("reqd")),
+        repeatable: modifiers.iter().any(|t| t.is_identifier("repeatable")),
+        negatable: modifiers.iter().any(|t| t.is_identifier("negatable")),
         statements: statements_in_block.unwrap_or_default(),
     })
 }
@@ -434,6 +630,11 @@ pub enum Statement {
     Arg(ArgStatement),
     Flag(FlagStatement),
 
+    // in top-level, sub
+    Exclusive(ExclusiveStatement),
+    Requires(RequiresStatement),
+    Set(SetStatement),
+
     // In arg, flag, defopts
     Opts(OptsStatement),
 
@@ -442,6 +643,7 @@ pub enum Statement {
 
     // In top-level
     Cmd(CmdStatement),
+    Extends,
     DefArgs(DefArgsStatement),
     DefOpts(DefOptsStatement),
 }
@@ -453,9 +655,12 @@ fn parse_statement_inside_sub(i: &mut &[Token]) -> PResult<Statement> {
         parse_sub_statement.map(Statement::Sub),
         parse_arg_statement.map(Statement::Arg),
         parse_flag_statement.map(Statement::Flag),
+        parse_exclusive_statement.map(Statement::Exclusive),
+        parse_requires_statement.map(Statement::Requires),
+        parse_set_statement.map(Statement::Set),
     ))
     .context(StrContext::Expected(StrContextValue::Description(
-        "desc, include, sub, arg, or flag statement",
+        "desc, include, sub, arg, flag, exclusive, requires, or set statement",
     )))
     .parse_next(i)
 }
@@ -488,6 +693,7 @@ fn parse_statement_inside_flag(i: &mut &[Token]) -> PResult<Statement> {
 fn parse_statement_top_level(i: &mut &[Token]) -> PResult<Statement> {
     alt((
         parse_cmd_statement.map(Statement::Cmd),
+        Token::Identifier("extends").map(|_| Statement::Extends),
         parse_desc_statement.map(Statement::Desc),
         parse_include_statement.map(Statement::Include),
         parse_sub_statement.map(Statement::Sub),
@@ -495,9 +701,13 @@ fn parse_statement_top_level(i: &mut &[Token]) -> PResult<Statement> {
         parse_flag_statement.map(Statement::Flag),
         parse_defargs_statement.map(Statement::DefArgs),
         parse_defopts_statement.map(Statement::DefOpts),
+        parse_exclusive_statement.map(Statement::Exclusive),
+        parse_requires_statement.map(Statement::Requires),
+        parse_set_statement.map(Statement::Set),
     ))
     .context(StrContext::Expected(StrContextValue::Description(
-        "cmd, desc, include, sub, arg, or flag statement",
+        "cmd, extends, desc, include, sub, arg, flag, defargs, defopts, exclusive, requires, or \
+         set statement",
     )))
     .parse_next(i)
 }
@@ -558,9 +768,11 @@ mod tests {
                 statements: vec![
                     Opts(OptsStatement::Const {
                         values: vec!["hello \"world\"".to_string(), "abc".to_string()],
+                        modifiers: vec![],
                     }),
                     Opts(OptsStatement::Const {
                         values: vec!["def".to_string()],
+                        modifiers: vec![],
                     }),
                 ],
             })],
@@ -568,4 +780,14 @@ mod tests {
 
         assert_eq!(parse_tree, expected);
     }
+
+    #[test]
+    fn test_top_level_statement_error_lists_all_statements() {
+        let tokens = vec![Token::Identifier("bogus")];
+        let err = parse_statement_top_level.parse(&tokens).unwrap_err();
+        assert!(format!("{:?}", err).contains(
+            "cmd, extends, desc, include, sub, arg, flag, defargs, defopts, exclusive, requires, \
+             or set statement"
+        ));
+    }
 }
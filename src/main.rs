@@ -1,5 +1,6 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueHint};
 use color_eyre::eyre::Result;
+use tabry::app::CompType;
 
 #[derive(Parser)]
 #[command(name = "tabry")]
@@ -13,6 +14,7 @@ struct Cli {
 #[derive(Subcommand)]
 enum Subcommands {
     /// Output completion script for bash
+    ///
     /// Usage in ~/.bash_profile: `source <(tabry bash)` or
     /// `source <(tabry bash --no-auto); _tabry_complete_one_command mycmd`
     Bash {
@@ -31,6 +33,7 @@ enum Subcommands {
     },
 
     /// Output completion script for zsh
+    ///
     /// Usage in ~/.zsh_profile: `source <(tabry zsh)` or
     /// `source <(tabry zsh --no-auto); _tabry_complete_one_command mycmd`
     Zsh {
@@ -49,6 +52,7 @@ enum Subcommands {
     },
 
     /// Output completion script for fish
+    ///
     /// Usage in ~/.config/fish/config.fish: `tabry fish | source` or
     /// `tabry fish | source; tabry_completion_init mycmd`
     Fish {
@@ -66,54 +70,219 @@ enum Subcommands {
         uniq_fn_id: Option<String>,
     },
 
-    /// List commands for which there is a .tabry/.json file in TABRY_IMPORT_PATH
+    /// List commands with configs, their aliases, and tabry itself
+    ///
+    /// Configs are .tabry/.json files in any of the config dirs (project .tabry dirs,
+    /// TABRY_IMPORT_PATH, XDG_DATA_HOME/tabry and XDG_DATA_DIRS/*/tabry).
     Commands,
 
+    /// Show which config file is used for a command, and which files it shadows
+    Which {
+        #[arg(value_hint = ValueHint::CommandName)]
+        /// The command name
+        command: String,
+    },
+
     /// Compile a tabry file to json (usually done automatically via tabry complete).
+    ///
     /// Usage: tabry compile < [tabry file] > [json file]
-    Compile,
+    Compile {
+        #[arg(long)]
+        /// Expand all includes in the output, checking for missing includes and include loops
+        /// (set TABRY_LINK=1 to do this for automatically compiled files too)
+        link: bool,
+    },
+
+    /// Check a full command line against the command's flag constraints
+    ///
+    /// The constraints are reqd flags, `exclusive` and `requires`. Prints violations and exits
+    /// non-zero if there are any.
+    /// Usage: tabry validate -- mycmd sub --flag arg
+    Validate {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = true,
+            value_hint = ValueHint::CommandWithArguments
+        )]
+        /// The command and its arguments
+        command_line: Vec<String>,
+    },
+
+    /// Check a tabry config against test cases in a TOML file
+    ///
+    /// Each case is a command line, with the cursor at `<TAB>`, and the expected options,
+    /// specials and/or machine state. `opts shell` commands aren't run; their output is taken
+    /// from the [stubs] table, by command.
+    /// Usage: tabry test mycmd.tabry mycmd-tests.toml [--update]
+    Test {
+        #[arg(value_hint = ValueHint::FilePath)]
+        /// The tabry (or JSON) config to test
+        spec: String,
+
+        #[arg(value_hint = ValueHint::FilePath)]
+        /// The TOML file with the test cases
+        cases: String,
+
+        #[arg(long)]
+        /// Replace failing expectations in the cases file with the actual results
+        update: bool,
+    },
+
+    /// Interactively show how command lines for a command are completed
+    ///
+    /// For each line typed, shows what each token was taken to be, the resulting machine state,
+    /// which options sources ran (with timings), and the options found.
+    Debug {
+        #[arg(value_hint = ValueHint::CommandName)]
+        /// The command name
+        command: String,
+    },
 
     /// Return completions (usually used via shell script)
     Complete {
-        /// TODO desc
+        /// The command line being completed
         compline: String,
-        /// TODO desc
+        /// The position of the cursor in the command line, in characters
         comppoint: String,
 
         /// Include descriptions in completions (for fish shell only)
         #[clap(long, short, action)]
         include_descriptions: bool,
+
+        /// Escape completions for the shell, to continue the token being completed (in any quote
+        /// opened in it), for shells which insert them as-is (bash and zsh)
+        #[arg(long)]
+        escape: bool,
+
+        /// The kind of completion (bash's COMP_TYPE, or normal, list or menu). When listing,
+        /// descriptions are shown in columns; otherwise only values are given. Unknown kinds are
+        /// treated as normal.
+        #[arg(long)]
+        comp_type: Option<CompType>,
+    },
+
+    /// Return completions given the words of the command line, as split by the shell
+    ///
+    /// The words are e.g. bash's COMP_WORDS, zsh's $words or fish's `commandline -opc`, rather
+    /// than the whole line (usually used via shell script).
+    CompleteWords {
+        /// Index of the word being completed (COMP_CWORD); may be the number of words
+        #[arg(long)]
+        cword: usize,
+
+        /// The part of the word being completed before the cursor, as typed (with any quotes).
+        /// Defaults to the word at --cword.
+        #[arg(long, allow_hyphen_values = true)]
+        cur: Option<String>,
+
+        /// The words are as typed (with any quotes and backslashes), as in bash and zsh, rather
+        /// than literal
+        #[arg(long)]
+        quoted: bool,
+
+        /// Read the words from stdin, NUL-separated, instead of arguments
+        #[arg(long, conflicts_with = "words")]
+        stdin: bool,
+
+        /// Include descriptions in completions (for fish shell only)
+        #[clap(long, short, action)]
+        include_descriptions: bool,
+
+        /// Escape completions for the shell, as for `complete --escape`
+        #[arg(long)]
+        escape: bool,
+
+        /// The kind of completion, as for `complete --comp-type`
+        #[arg(long)]
+        comp_type: Option<CompType>,
+
+        /// The words of the command line, starting with the command
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        words: Vec<String>,
     },
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
+    tabry::app::init_logging();
 
     use tabry::app::*;
     use Subcommands::*;
+    set_self_command(Cli::command());
     let cli = Cli::parse();
     match cli.command {
         Complete {
             compline,
             comppoint,
             include_descriptions,
-        } => run_as_compline(&compline, &comppoint, include_descriptions)?,
-        Compile => compile()?,
-        Commands => commands(),
+            escape,
+            comp_type,
+        } => run_as_compline(
+            &compline,
+            &comppoint,
+            &CompletionOutput {
+                include_descriptions,
+                escape,
+                comp_type,
+            },
+        )?,
+        CompleteWords {
+            cword,
+            cur,
+            quoted,
+            stdin,
+            include_descriptions,
+            escape,
+            comp_type,
+            words,
+        } => run_with_words(
+            (!stdin).then_some(words),
+            cword,
+            cur.as_deref(),
+            quoted,
+            &CompletionOutput {
+                include_descriptions,
+                escape,
+                comp_type,
+            },
+        )?,
+        Compile { link } => compile(link)?,
+        Validate { command_line } => {
+            if !validate(&command_line)? {
+                std::process::exit(1);
+            }
+        }
+        Test {
+            spec,
+            cases,
+            update,
+        } => {
+            if !run_tests(&spec, &cases, update)? {
+                std::process::exit(1);
+            }
+        }
+        Debug { command } => debug_repl(&command)?,
+        Commands => commands()?,
+        Which { command } => {
+            if !which(&command) {
+                std::process::exit(1);
+            }
+        }
         Bash {
             import_path,
             no_auto,
-            uniq_fn_id
+            uniq_fn_id,
         } => bash(import_path.as_deref(), no_auto, uniq_fn_id.as_deref()),
         Zsh {
             import_path,
             no_auto,
-            uniq_fn_id
+            uniq_fn_id,
         } => zsh(import_path.as_deref(), no_auto, uniq_fn_id.as_deref()),
         Fish {
             import_path,
             no_auto,
-            uniq_fn_id
+            uniq_fn_id,
         } => fish(import_path.as_deref(), no_auto, uniq_fn_id.as_deref()),
     }
     Ok(())